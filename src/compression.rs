@@ -0,0 +1,163 @@
+use crate::{config, proto::{Compression, FileCompression}};
+
+/// zstd decoders skip frames with this magic, so the stored file is still a valid zstd stream.
+const SKIPPABLE_FRAME_MAGIC: u32 = 0x184D2A50;
+
+const SKIPPABLE_FRAME_HEADER_SIZE: u64 = 8;
+
+const SEEK_TABLE_ENTRY_SIZE: u64 = 8;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Parses the `compression` parameter of upload APIs.
+pub fn parse_compression(input: &str) -> Option<Compression> {
+    match input {
+        "zstd" => Some(Compression::Zstd),
+        _ => None,
+    }
+}
+
+pub fn compression_name(compression: &FileCompression) -> &'static str {
+    match Compression::try_from(compression.algorithm) {
+        Ok(Compression::Zstd) => "zstd",
+        Ok(Compression::None) | Err(_) => "none",
+    }
+}
+
+/// Settings for a new upload. Sizes are filled when the upload is finished.
+pub fn new_file_compression(algorithm: Compression) -> FileCompression {
+    FileCompression {
+        algorithm: algorithm as i32,
+        original_size: 0,
+        // the same as chunks of uncompressed files, so that the chunk API works in the same way
+        frame_size: config::get().download_chunk_size as i64,
+        seek_table_offset: 0,
+    }
+}
+
+pub fn compress_frame(content: &[u8]) -> Vec<u8> {
+    // compressing into a memory buffer doesn't fail
+    zstd::bulk::compress(content, ZSTD_LEVEL).unwrap()
+}
+
+pub fn decompress_frame(compression: &FileCompression, frame: &[u8]) -> Option<Vec<u8>> {
+    match zstd::bulk::decompress(frame, compression.frame_size as usize) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            println!("failed to decompress frame {:?}", e);
+            None
+        }
+    }
+}
+
+/// Builds the skippable frame which has the stored offset of every frame, and the end of the last one.
+pub fn seek_table_frame(offsets: &[u64]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(SKIPPABLE_FRAME_HEADER_SIZE as usize + offsets.len() * SEEK_TABLE_ENTRY_SIZE as usize);
+    frame.extend_from_slice(&SKIPPABLE_FRAME_MAGIC.to_le_bytes());
+    frame.extend_from_slice(&((offsets.len() as u64 * SEEK_TABLE_ENTRY_SIZE) as u32).to_le_bytes());
+    for offset in offsets {
+        frame.extend_from_slice(&offset.to_le_bytes());
+    }
+    frame
+}
+
+/// Returns the stored range of the seek table entries which locate the frame (its start and end).
+pub fn seek_table_entries(compression: &FileCompression, frame: u64) -> (u64, u64) {
+    let start = compression.seek_table_offset as u64 + SKIPPABLE_FRAME_HEADER_SIZE + frame * SEEK_TABLE_ENTRY_SIZE;
+    (start, start + SEEK_TABLE_ENTRY_SIZE * 2)
+}
+
+/// Reads the range which `seek_table_entries` returned.
+pub fn parse_seek_table_entries(entries: &[u8]) -> Option<(u64, u64)> {
+    let start = u64::from_le_bytes(entries.get(0..8)?.try_into().ok()?);
+    let end = u64::from_le_bytes(entries.get(8..16)?.try_into().ok()?);
+    if end < start {
+        return None;
+    }
+    Some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_compression(frame_size: i64, seek_table_offset: i64) -> FileCompression {
+        FileCompression {
+            algorithm: Compression::Zstd as i32,
+            original_size: 0,
+            frame_size,
+            seek_table_offset,
+        }
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(parse_compression("zstd"), Some(Compression::Zstd));
+        assert_eq!(parse_compression("gzip"), None);
+        assert_eq!(compression_name(&file_compression(4096, 0)), "zstd");
+        assert_eq!(compression_name(&FileCompression { algorithm: 100, ..file_compression(4096, 0) }), "none");
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let content: Vec<u8> = (0..4096u32).map(|x| (x % 7) as u8).collect();
+        let compressed = compress_frame(&content);
+        assert!(compressed.len() < content.len());
+        assert_eq!(decompress_frame(&file_compression(4096, 0), &compressed), Some(content.clone()));
+
+        // a frame larger than frame_size or a broken one isn't decompressed
+        assert_eq!(decompress_frame(&file_compression(1024, 0), &compressed), None);
+        assert_eq!(decompress_frame(&file_compression(4096, 0), &compressed[..compressed.len() / 2]), None);
+        assert_eq!(decompress_frame(&file_compression(4096, 0), b"not a zstd frame"), None);
+    }
+
+    #[test]
+    fn seek_table_layout() {
+        let frame = seek_table_frame(&[0, 100, 250]);
+        assert_eq!(frame.len(), 8 + 3 * 8);
+        assert_eq!(&frame[0..4], &SKIPPABLE_FRAME_MAGIC.to_le_bytes());
+        assert_eq!(&frame[4..8], &24u32.to_le_bytes());
+        assert_eq!(&frame[16..24], &100u64.to_le_bytes());
+    }
+
+    #[test]
+    fn seek_table_round_trip() {
+        let offsets = [0, 100, 250, 251];
+        let table = seek_table_frame(&offsets);
+        let compression = file_compression(4096, 1000);
+        let mut stored = vec![0; 1000];
+        stored.extend_from_slice(&table);
+
+        for frame in 0..3 {
+            let (start, end) = seek_table_entries(&compression, frame);
+            let entries = &stored[start as usize..end as usize];
+            assert_eq!(parse_seek_table_entries(entries), Some((offsets[frame as usize], offsets[frame as usize + 1])));
+        }
+        // the entries of the last frame end at the end of the table
+        assert_eq!(seek_table_entries(&compression, 2).1, stored.len() as u64);
+    }
+
+    #[test]
+    fn broken_seek_table() {
+        let table = seek_table_frame(&[100, 50]);
+        assert_eq!(parse_seek_table_entries(&table[8..]), None);
+        assert_eq!(parse_seek_table_entries(&table[8..20]), None);
+        assert_eq!(parse_seek_table_entries(&[]), None);
+    }
+
+    #[test]
+    fn stored_file_is_zstd_stream() {
+        let frames = [vec![1u8; 3000], vec![2u8; 1000]];
+        let mut stored = Vec::new();
+        let mut offsets = Vec::new();
+        for frame in &frames {
+            offsets.push(stored.len() as u64);
+            stored.extend_from_slice(&compress_frame(frame));
+        }
+        offsets.push(stored.len() as u64);
+        stored.extend_from_slice(&seek_table_frame(&offsets));
+
+        // decoders skip the seek table
+        assert_eq!(zstd::decode_all(&stored[..]).unwrap(), frames.concat());
+    }
+}
@@ -0,0 +1,103 @@
+use std::{path::PathBuf, sync::OnceLock, time::Duration};
+
+pub struct Config {
+    /// Bearer token required by the debug/admin endpoints. They are disabled when unset.
+    pub admin_token: Option<String>,
+    /// Unfinished uploads are forgotten after being idle for this long.
+    pub upload_idle_ttl: Duration,
+    /// Where teleton keeps its own state (e.g. the deduplication index).
+    pub state_dir: PathBuf,
+    /// Key for sealing refs and captions which carry file keys. Encryption at rest is unavailable without it.
+    pub secret_key: Option<[u8; 32]>,
+    /// Hosts which `/v1/upload/from-url` can fetch from (`.example.com` also allows subdomains). It's disabled when empty.
+    pub fetch_allowed_hosts: Vec<String>,
+    /// Max size of a file fetched by `/v1/upload/from-url`, in addition to the upload limit.
+    pub fetch_max_size: Option<u64>,
+    pub fetch_max_redirects: usize,
+    /// Connections opened in addition to the main one, for file transfer RPCs.
+    pub transfer_connections: usize,
+    /// Size of parts sent to upstream, which `/v1/upload/start` uses at most.
+    pub upload_chunk_size: usize,
+    /// Size of chunks which the files endpoints serve by default.
+    pub download_chunk_size: usize,
+}
+
+impl Config {
+    fn from_env() -> Config {
+        let admin_token = match std::env::var("TELETON_ADMIN_TOKEN") {
+            Ok(v) if !v.is_empty() => Some(v),
+            _ => None,
+        };
+
+        let upload_idle_ttl = match std::env::var("TELETON_UPLOAD_IDLE_TTL") {
+            Ok(v) => Duration::from_secs(v.parse().expect("Failed to parse TELETON_UPLOAD_IDLE_TTL (should be seconds)")),
+            Err(_) => Duration::from_secs(6 * 60 * 60),
+        };
+
+        // next to the session file by default
+        let state_dir = match std::env::var("TELETON_STATE_DIR") {
+            Ok(v) if !v.is_empty() => PathBuf::from(v),
+            _ => std::env::var("TELETON_SESSION_PATH").ok()
+                .and_then(|x| PathBuf::from(x).parent().map(|x| x.to_path_buf()))
+                .unwrap_or_else(|| PathBuf::from(".")),
+        };
+
+        let secret_key = match std::env::var("TELETON_SECRET_KEY") {
+            Ok(v) if !v.is_empty() => {
+                let key = crate::shared::from_hex(&v).and_then(|x| <[u8; 32]>::try_from(x).ok());
+                Some(key.expect("Failed to parse TELETON_SECRET_KEY (should be 64 hex characters)"))
+            },
+            _ => None,
+        };
+
+        let fetch_allowed_hosts = match std::env::var("TELETON_FETCH_ALLOWED_HOSTS") {
+            Ok(v) => v.split(',').map(|x| x.trim().to_ascii_lowercase()).filter(|x| !x.is_empty()).collect(),
+            Err(_) => vec![],
+        };
+
+        let fetch_max_size = match std::env::var("TELETON_FETCH_MAX_SIZE") {
+            Ok(v) if !v.is_empty() => Some(v.parse().expect("Failed to parse TELETON_FETCH_MAX_SIZE (should be bytes)")),
+            _ => None,
+        };
+
+        let fetch_max_redirects = match std::env::var("TELETON_FETCH_MAX_REDIRECTS") {
+            Ok(v) => v.parse().expect("Failed to parse TELETON_FETCH_MAX_REDIRECTS"),
+            Err(_) => 5,
+        };
+
+        let transfer_connections = match std::env::var("TELETON_TRANSFER_CONNECTIONS") {
+            Ok(v) => v.parse().expect("Failed to parse TELETON_TRANSFER_CONNECTIONS"),
+            Err(_) => 0,
+        };
+
+        let upload_chunk_size = match std::env::var("TELETON_UPLOAD_CHUNK_SIZE") {
+            Ok(v) => v.parse().ok().filter(|x| crate::shared::is_valid_part_size(*x)).expect("Failed to parse TELETON_UPLOAD_CHUNK_SIZE (should be a power of two from 4096 to 524288)"),
+            Err(_) => crate::shared::MAX_PART_SIZE,
+        };
+
+        let download_chunk_size = match std::env::var("TELETON_DOWNLOAD_CHUNK_SIZE") {
+            Ok(v) => v.parse().ok().filter(|x| crate::shared::is_valid_download_chunk_size(*x)).expect("Failed to parse TELETON_DOWNLOAD_CHUNK_SIZE (should be a power of two from 4096 to 1048576)"),
+            // larger chunks are opt-in, since clients may read at offsets of the original chunk size
+            Err(_) => crate::shared::MAX_PART_SIZE,
+        };
+
+        Config {
+            admin_token,
+            upload_idle_ttl,
+            state_dir,
+            secret_key,
+            fetch_allowed_hosts,
+            fetch_max_size,
+            fetch_max_redirects,
+            transfer_connections,
+            upload_chunk_size,
+            download_chunk_size,
+        }
+    }
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::from_env)
+}
@@ -0,0 +1,195 @@
+use aes_gcm::{aead::{Aead, KeyInit}, Aes256Gcm};
+use chacha20poly1305::ChaCha20Poly1305;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+use crate::{config, proto::{Cipher, FileEncryption}};
+
+/// Size of the authentication tag which is appended to every encrypted part.
+pub const TAG_SIZE: usize = 16;
+
+const NONCE_SIZE: usize = 12;
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    StdRng::from_entropy().fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Encrypts teleton's own data (refs, captions) with the server key. Returns `None` if the key isn't configured.
+pub fn seal(plaintext: &[u8]) -> Option<Vec<u8>> {
+    seal_with(config::get().secret_key.as_ref()?, plaintext)
+}
+
+/// Reverses `seal`. Returns `None` if the key isn't configured or the data is broken.
+pub fn unseal(sealed: &[u8]) -> Option<Vec<u8>> {
+    unseal_with(config::get().secret_key.as_ref()?, sealed)
+}
+
+fn seal_with(key: &[u8; 32], plaintext: &[u8]) -> Option<Vec<u8>> {
+    let nonce = random_bytes(NONCE_SIZE);
+    let ciphertext = Aes256Gcm::new(key.into()).encrypt(nonce[..].into(), plaintext).ok()?;
+    Some([nonce, ciphertext].concat())
+}
+
+fn unseal_with(key: &[u8; 32], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_SIZE {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+    Aes256Gcm::new(key.into()).decrypt(nonce.into(), ciphertext).ok()
+}
+
+/// Parses the `encryption` parameter of upload APIs.
+pub fn parse_cipher(input: &str) -> Option<Cipher> {
+    match input {
+        "aes-256-gcm" => Some(Cipher::Aes256Gcm),
+        "chacha20-poly1305" => Some(Cipher::Chacha20Poly1305),
+        _ => None,
+    }
+}
+
+/// Reverses `parse_cipher`, for showing which cipher a file uses without its key.
+pub fn cipher_name(encryption: &FileEncryption) -> &'static str {
+    match Cipher::try_from(encryption.cipher) {
+        Ok(Cipher::Aes256Gcm) => "aes-256-gcm",
+        Ok(Cipher::Chacha20Poly1305) => "chacha20-poly1305",
+        Ok(Cipher::None) | Err(_) => "none",
+    }
+}
+
+/// Generates a random key for a new file.
+pub fn new_file_encryption(cipher: Cipher) -> FileEncryption {
+    FileEncryption {
+        cipher: cipher as i32,
+        key: random_bytes(32),
+        nonce_prefix: random_bytes(4),
+        // set with the part size of the upload
+        part_size: 0,
+    }
+}
+
+/// Returns `None` if the prefix is malformed, since the ciphers panic on a nonce of a wrong size.
+fn part_nonce(encryption: &FileEncryption, part: u64) -> Option<Vec<u8>> {
+    let nonce = [&encryption.nonce_prefix[..], &part.to_be_bytes()].concat();
+    match nonce.len() {
+        NONCE_SIZE => Some(nonce),
+        _ => None,
+    }
+}
+
+/// Encrypts a part of the file. The result is `TAG_SIZE` bytes longer than `plaintext`.
+pub fn encrypt_part(encryption: &FileEncryption, part: u64, plaintext: &[u8]) -> Option<Vec<u8>> {
+    match Cipher::try_from(encryption.cipher).ok()? {
+        Cipher::None => Some(plaintext.to_vec()),
+        Cipher::Aes256Gcm => Aes256Gcm::new_from_slice(&encryption.key).ok()?.encrypt(part_nonce(encryption, part)?[..].into(), plaintext).ok(),
+        Cipher::Chacha20Poly1305 => ChaCha20Poly1305::new_from_slice(&encryption.key).ok()?.encrypt(part_nonce(encryption, part)?[..].into(), plaintext).ok(),
+    }
+}
+
+/// Decrypts a part of the file. Returns `None` if it's tampered with (or the key is wrong).
+pub fn decrypt_part(encryption: &FileEncryption, part: u64, ciphertext: &[u8]) -> Option<Vec<u8>> {
+    match Cipher::try_from(encryption.cipher).ok()? {
+        Cipher::None => Some(ciphertext.to_vec()),
+        Cipher::Aes256Gcm => Aes256Gcm::new_from_slice(&encryption.key).ok()?.decrypt(part_nonce(encryption, part)?[..].into(), ciphertext).ok(),
+        Cipher::Chacha20Poly1305 => ChaCha20Poly1305::new_from_slice(&encryption.key).ok()?.decrypt(part_nonce(encryption, part)?[..].into(), ciphertext).ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_round_trip() {
+        let key = [1u8; 32];
+        let sealed = seal_with(&key, b"caption").unwrap();
+        assert_eq!(sealed.len(), NONCE_SIZE + b"caption".len() + TAG_SIZE);
+        assert_eq!(unseal_with(&key, &sealed).unwrap(), b"caption");
+        // nonces are random, so the same data is sealed differently
+        assert_ne!(seal_with(&key, b"caption").unwrap(), sealed);
+    }
+
+    #[test]
+    fn unseal_rejects_broken_data() {
+        let key = [1u8; 32];
+        let sealed = seal_with(&key, b"caption").unwrap();
+
+        assert!(unseal_with(&[2u8; 32], &sealed).is_none());
+        for i in [0, NONCE_SIZE, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert!(unseal_with(&key, &tampered).is_none());
+        }
+        assert!(unseal_with(&key, &sealed[..sealed.len() - 1]).is_none());
+        assert!(unseal_with(&key, &sealed[..NONCE_SIZE - 1]).is_none());
+    }
+
+    #[test]
+    fn ciphers() {
+        assert_eq!(parse_cipher("aes-256-gcm"), Some(Cipher::Aes256Gcm));
+        assert_eq!(parse_cipher("chacha20-poly1305"), Some(Cipher::Chacha20Poly1305));
+        assert_eq!(parse_cipher("AES-256-GCM"), None);
+        assert_eq!(parse_cipher("none"), None);
+
+        for name in ["aes-256-gcm", "chacha20-poly1305"] {
+            assert_eq!(cipher_name(&new_file_encryption(parse_cipher(name).unwrap())), name);
+        }
+        assert_eq!(cipher_name(&FileEncryption::default()), "none");
+    }
+
+    #[test]
+    fn part_nonces() {
+        let encryption = FileEncryption { nonce_prefix: vec![1, 2, 3, 4], ..Default::default() };
+        assert_eq!(part_nonce(&encryption, 0).unwrap(), [1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(part_nonce(&encryption, 0x0102).unwrap(), [1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert!(part_nonce(&FileEncryption { nonce_prefix: vec![1, 2, 3], ..Default::default() }, 0).is_none());
+    }
+
+    #[test]
+    fn part_round_trip() {
+        for cipher in [Cipher::Aes256Gcm, Cipher::Chacha20Poly1305] {
+            let encryption = new_file_encryption(cipher);
+            let encrypted = encrypt_part(&encryption, 3, b"part content").unwrap();
+            assert_eq!(encrypted.len(), b"part content".len() + TAG_SIZE);
+            assert_eq!(decrypt_part(&encryption, 3, &encrypted).unwrap(), b"part content");
+        }
+    }
+
+    #[test]
+    fn decrypt_part_rejects_broken_data() {
+        for cipher in [Cipher::Aes256Gcm, Cipher::Chacha20Poly1305] {
+            let encryption = new_file_encryption(cipher);
+            let encrypted = encrypt_part(&encryption, 3, b"part content").unwrap();
+
+            let mut tampered = encrypted.clone();
+            tampered[0] ^= 1;
+            assert!(decrypt_part(&encryption, 3, &tampered).is_none());
+            // parts can't be swapped, since the nonce has the part number
+            assert!(decrypt_part(&encryption, 4, &encrypted).is_none());
+            let other_key = FileEncryption { key: new_file_encryption(cipher).key, ..encryption.clone() };
+            assert!(decrypt_part(&other_key, 3, &encrypted).is_none());
+            let other_prefix = FileEncryption { nonce_prefix: vec![0; 4], ..encryption.clone() };
+            assert!(decrypt_part(&other_prefix, 3, &encrypted).is_none());
+        }
+    }
+
+    #[test]
+    fn malformed_file_key() {
+        let encryption = FileEncryption { key: vec![0; 31], ..new_file_encryption(Cipher::Aes256Gcm) };
+        assert!(encrypt_part(&encryption, 0, b"part content").is_none());
+        assert!(decrypt_part(&encryption, 0, b"part content").is_none());
+
+        let encryption = FileEncryption { cipher: 100, ..new_file_encryption(Cipher::Aes256Gcm) };
+        assert!(encrypt_part(&encryption, 0, b"part content").is_none());
+
+        let encryption = FileEncryption { nonce_prefix: vec![0; 5], ..new_file_encryption(Cipher::Chacha20Poly1305) };
+        assert!(encrypt_part(&encryption, 0, b"part content").is_none());
+        assert!(decrypt_part(&encryption, 0, b"part content").is_none());
+    }
+
+    #[test]
+    fn no_cipher() {
+        let encryption = FileEncryption::default();
+        assert_eq!(encrypt_part(&encryption, 0, b"part content").unwrap(), b"part content");
+    }
+}
@@ -0,0 +1,270 @@
+use std::{collections::{BTreeSet, HashMap}, path::PathBuf};
+
+use grammers_client::Client;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+use crate::{proto::FileRef, shared::{get_composite_file_ref, get_message, message_to_file_ref}, store::JsonFileStore};
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct DedupEntry {
+    /// ref without a copy id
+    r#ref: String,
+    /// how many logical copies without a copy id share this ref (the ones made before copy ids)
+    refcount: u32,
+    /// copy ids of the logical copies which share this ref, so that deleting one twice doesn't drop another
+    #[serde(default)]
+    copies: BTreeSet<u64>,
+}
+
+impl DedupEntry {
+    fn count(&self) -> u32 {
+        self.refcount + self.copies.len() as u32
+    }
+
+    /// Adds a logical copy, and returns the ref of it.
+    fn add_copy(&mut self, mut file_ref: FileRef) -> FileRef {
+        let copy_id = loop {
+            let id = StdRng::from_entropy().next_u64();
+            if id != 0 && !self.copies.contains(&id) {
+                break id;
+            }
+        };
+        self.copies.insert(copy_id);
+        file_ref.set_copy_id(0);
+        self.r#ref = file_ref.to_ref_string();
+        file_ref.set_copy_id(copy_id);
+        file_ref
+    }
+}
+
+/// Maps the SHA-256 of stored files (hex) to their refs, so that identical files are stored only once.
+pub struct DedupIndex {
+    entries: JsonFileStore<HashMap<String, DedupEntry>>,
+}
+
+impl DedupIndex {
+    pub fn load(path: PathBuf) -> DedupIndex {
+        DedupIndex {
+            entries: JsonFileStore::load(path),
+        }
+    }
+
+    /// Returns the ref and its reference count.
+    pub fn get(&self, sha256: &str) -> Option<(String, u32)> {
+        self.entries.read(|entries| entries.get(&sha256.to_ascii_lowercase()).map(|x| (x.r#ref.clone(), x.count())))
+    }
+
+    /// Records a newly stored file as its first copy, and returns the ref of the copy.
+    /// If another file with the same content is already recorded, it's kept and the ref is returned as is.
+    pub fn insert(&self, sha256: &str, file_ref: FileRef) -> FileRef {
+        self.entries.update(|entries| {
+            let key = sha256.to_ascii_lowercase();
+            if entries.contains_key(&key) {
+                return file_ref;
+            }
+            let mut entry = DedupEntry { r#ref: String::new(), refcount: 0, copies: BTreeSet::new() };
+            let file_ref = entry.add_copy(file_ref);
+            entries.insert(key, entry);
+            file_ref
+        })
+    }
+
+    /// Adds a copy of the existing file, and returns the ref of it. `file_ref` may have a newer file reference than the recorded one.
+    pub fn acquire(&self, sha256: &str, file_ref: FileRef) -> Option<FileRef> {
+        self.entries.update(|entries| {
+            let entry = entries.get_mut(&sha256.to_ascii_lowercase())?;
+            Some(entry.add_copy(file_ref))
+        })
+    }
+
+    /// Drops the copy, and returns how many are left. The entry is removed when nothing is left.
+    ///
+    /// Releasing the same copy again doesn't change anything, so that a retried delete doesn't drop another copy.
+    pub fn release(&self, sha256: &str, copy_id: u64) -> Option<u32> {
+        self.entries.update(|entries| {
+            let key = sha256.to_ascii_lowercase();
+            let entry = entries.get_mut(&key)?;
+            let released = match copy_id {
+                0 if entry.refcount > 0 => {
+                    entry.refcount -= 1;
+                    true
+                },
+                0 => false,
+                copy_id => entry.copies.remove(&copy_id),
+            };
+            let left = entry.count();
+            if released && left == 0 {
+                entries.remove(&key);
+            }
+            Some(left)
+        })
+    }
+
+    /// Forgets the file, e.g. when it's found to be deleted from upstream.
+    pub fn remove(&self, sha256: &str) {
+        self.entries.update(|entries| {
+            entries.remove(&sha256.to_ascii_lowercase());
+        });
+    }
+
+    /// Returns the stored file which has the same content, and adds a copy of it.
+    pub async fn reuse(&self, client: &Client, sha256: &str) -> Option<FileRef> {
+        let current = self.find(client, sha256).await?;
+        self.acquire(sha256, current)
+    }
+
+    /// Returns the stored file which has the same content (with the latest file reference), without adding a copy.
+    pub async fn find(&self, client: &Client, sha256: &str) -> Option<FileRef> {
+        let (existing, _) = self.get(sha256)?;
+        let existing = FileRef::from_ref_string(existing)?;
+
+        // make sure it still exists, which also gives the latest file reference
+        let current = match (&existing.v1, &existing.composite_v1) {
+            (Some(v1), _) => match get_message(client, v1.peer.as_ref(), v1.message_id).await {
+                Ok(message) => message.and_then(|x| message_to_file_ref(&x))
+                    .filter(|x| x.v1.as_ref().map(|x| x.document_id) == Some(v1.document_id)),
+                Err(e) => {
+                    // keep the entry, it's likely to be a temporary error
+                    println!("failed to get message {:?}", e);
+                    return None;
+                }
+            },
+            (None, Some(composite)) => match get_composite_file_ref(client, composite.peer.as_ref(), composite.manifest_message_id).await {
+                Ok(v) => v.map(|x| x.1),
+                Err(e) => {
+                    println!("failed to get manifest {:?}", e);
+                    return None;
+                }
+            },
+            (None, None) => None,
+        };
+
+        if current.is_none() {
+            println!("deduplicated file {} is gone from upstream, forgetting it", sha256);
+            self.remove(sha256);
+        }
+        current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::FileRefV1;
+
+    use super::*;
+
+    const SHA256: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("teleton-dedup-test-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn file_ref(message_id: i32) -> FileRef {
+        FileRef {
+            v1: Some(FileRefV1 { message_id, ..Default::default() }),
+            ..Default::default()
+        }
+    }
+
+    fn message_id(r#ref: &str) -> i32 {
+        FileRef::from_ref_string(r#ref.to_string()).and_then(|x| x.v1).unwrap().message_id
+    }
+
+    #[test]
+    fn insert_and_acquire() {
+        let index = DedupIndex::load(temp_path("acquire"));
+        assert!(index.get(SHA256).is_none());
+
+        let first = index.insert(SHA256, file_ref(1));
+        assert_ne!(first.copy_id(), 0);
+        assert_eq!(index.get(SHA256).map(|x| x.1), Some(1));
+
+        let second = index.acquire(SHA256, file_ref(1)).unwrap();
+        assert_ne!(second.copy_id(), 0);
+        assert_ne!(second.copy_id(), first.copy_id());
+
+        // the index keeps the ref without a copy id, with any case of the hash
+        let (r#ref, count) = index.get(&SHA256.to_ascii_uppercase()).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(FileRef::from_ref_string(r#ref).map(|x| x.copy_id()), Some(0));
+    }
+
+    #[test]
+    fn insert_keeps_existing_entry() {
+        let index = DedupIndex::load(temp_path("insert"));
+        index.insert(SHA256, file_ref(1));
+
+        // e.g. the same content was uploaded concurrently, so it's a separate file
+        let other = index.insert(SHA256, file_ref(2));
+        assert_eq!(other.copy_id(), 0);
+        let (r#ref, count) = index.get(SHA256).unwrap();
+        assert_eq!((message_id(&r#ref), count), (1, 1));
+    }
+
+    #[test]
+    fn acquire_without_entry() {
+        let index = DedupIndex::load(temp_path("acquire-missing"));
+        assert!(index.acquire(SHA256, file_ref(1)).is_none());
+    }
+
+    #[test]
+    fn release_counts() {
+        let index = DedupIndex::load(temp_path("release"));
+        let first = index.insert(SHA256, file_ref(1));
+        let second = index.acquire(SHA256, file_ref(1)).unwrap();
+
+        assert_eq!(index.release(SHA256, first.copy_id()), Some(1));
+        assert_eq!(index.release(SHA256, second.copy_id()), Some(0));
+        assert!(index.get(SHA256).is_none());
+        assert_eq!(index.release(SHA256, second.copy_id()), None);
+    }
+
+    #[test]
+    fn double_delete_releases_once() {
+        let index = DedupIndex::load(temp_path("double"));
+        let first = index.insert(SHA256, file_ref(1));
+        let second = index.acquire(SHA256, file_ref(1)).unwrap();
+
+        assert_eq!(index.release(SHA256, first.copy_id()), Some(1));
+        assert_eq!(index.release(SHA256, first.copy_id()), Some(1));
+        // a ref which the index doesn't know doesn't drop others either
+        assert_eq!(index.release(SHA256, 12345), Some(1));
+        assert_eq!(index.get(SHA256).map(|x| x.1), Some(1));
+        assert_eq!(index.release(SHA256, second.copy_id()), Some(0));
+    }
+
+    #[test]
+    fn legacy_refcount() {
+        let path = temp_path("legacy");
+        let legacy = serde_json::json!({ SHA256: { "ref": file_ref(1).to_ref_string(), "refcount": 2 } });
+        std::fs::write(&path, serde_json::to_vec(&legacy).unwrap()).unwrap();
+
+        let index = DedupIndex::load(path.clone());
+        assert_eq!(index.get(SHA256).map(|x| x.1), Some(2));
+        let copy = index.acquire(SHA256, file_ref(1)).unwrap();
+        assert_eq!(index.get(SHA256).map(|x| x.1), Some(3));
+
+        // refs without a copy id release the ones counted before copy ids
+        assert_eq!(index.release(SHA256, 0), Some(2));
+        assert_eq!(index.release(SHA256, 0), Some(1));
+        assert_eq!(index.release(SHA256, 0), Some(1));
+        assert_eq!(index.release(SHA256, copy.copy_id()), Some(0));
+        assert!(index.get(SHA256).is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn persists_copies() {
+        let path = temp_path("persist");
+        let index = DedupIndex::load(path.clone());
+        let first = index.insert(SHA256, file_ref(1));
+        index.acquire(SHA256, file_ref(1)).unwrap();
+
+        let loaded = DedupIndex::load(path.clone());
+        assert_eq!(loaded.get(SHA256).map(|x| x.1), Some(2));
+        assert_eq!(loaded.release(SHA256, first.copy_id()), Some(1));
+        std::fs::remove_file(path).unwrap();
+    }
+}
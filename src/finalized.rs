@@ -0,0 +1,43 @@
+use std::{collections::HashMap, path::PathBuf, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::store::JsonFileStore;
+
+/// How long the result of a finalize is remembered for retries.
+const RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct FinalizedEntry {
+    r#ref: String,
+    /// unix time in seconds
+    finalized_at: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Maps `file_id` of finalized uploads to their refs, so that a retried finalize returns the same ref.
+pub struct FinalizedUploads {
+    entries: JsonFileStore<HashMap<String, FinalizedEntry>>,
+}
+
+impl FinalizedUploads {
+    pub fn load(path: PathBuf) -> FinalizedUploads {
+        FinalizedUploads {
+            entries: JsonFileStore::load(path),
+        }
+    }
+
+    pub fn get(&self, file_id: i64) -> Option<String> {
+        self.entries.read(|entries| entries.get(&file_id.to_string()).map(|x| x.r#ref.clone()))
+    }
+
+    /// Remembers the ref, and forgets ones older than the retention.
+    pub fn insert(&self, file_id: i64, r#ref: String) {
+        let now = now();
+        self.entries.update(|entries| {
+            entries.retain(|_, x| x.finalized_at + RETENTION.as_secs() > now);
+            entries.insert(file_id.to_string(), FinalizedEntry { r#ref, finalized_at: now });
+        });
+    }
+}
@@ -0,0 +1,5 @@
+mod transfers;
+mod uploads;
+
+pub use transfers::list_transfers;
+pub use uploads::list_uploads;
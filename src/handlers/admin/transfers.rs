@@ -0,0 +1,23 @@
+use axum::{body::Body, http::HeaderMap, response::Response};
+
+use crate::{shared::check_admin_token, transfers::{ConnectionSummary, TransferPool}};
+
+#[derive(serde::Serialize)]
+struct ListTransfersResponse {
+    connections: Vec<ConnectionSummary>,
+}
+
+pub async fn list_transfers(transfers: &TransferPool, headers: &HeaderMap) -> Response {
+    if let Some(res) = check_admin_token(headers) {
+        return res;
+    }
+
+    let res = ListTransfersResponse { connections: transfers.list() };
+    let res = serde_json::to_vec(&res).unwrap();
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(res))
+        .unwrap()
+}
@@ -0,0 +1,26 @@
+use axum::{body::Body, http::HeaderMap, response::Response};
+
+use crate::{shared::check_admin_token, uploads::{UploadRegistry, UploadSummary}};
+
+#[derive(serde::Serialize)]
+struct ListUploadsResponse {
+    uploads: Vec<UploadSummary>,
+}
+
+pub async fn list_uploads(uploads: &UploadRegistry, headers: &HeaderMap) -> Response {
+    if let Some(res) = check_admin_token(headers) {
+        return res;
+    }
+
+    let mut uploads = uploads.list();
+    uploads.sort_by_key(|x| x.idle_secs);
+
+    let res = ListUploadsResponse { uploads };
+    let res = serde_json::to_vec(&res).unwrap();
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(res))
+        .unwrap()
+}
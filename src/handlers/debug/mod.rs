@@ -0,0 +1,3 @@
+mod refs;
+
+pub use refs::{inspect_ref, InspectRefQueryParams};
@@ -0,0 +1,25 @@
+use axum::{body::Body, http::HeaderMap, response::Response};
+use grammers_client::Client;
+
+use crate::{inspect::inspect, shared::check_admin_token};
+
+#[derive(serde::Deserialize)]
+pub struct InspectRefQueryParams {
+    #[serde(default)]
+    live: bool,
+}
+
+pub async fn inspect_ref(client: &Client, headers: &HeaderMap, input: String, query: InspectRefQueryParams) -> Response {
+    if let Some(res) = check_admin_token(headers) {
+        return res;
+    }
+
+    let res = inspect(&input, if query.live { Some(client) } else { None }).await;
+    let res = serde_json::to_vec(&res).unwrap();
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(res))
+        .unwrap()
+}
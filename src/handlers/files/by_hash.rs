@@ -0,0 +1,51 @@
+use axum::{body::Body, response::Response};
+use grammers_client::Client;
+
+use crate::{dedup::DedupIndex, handlers::upload::UploadFinalizeResponse, shared::from_hex};
+
+fn parse_sha256(input: &str) -> Option<Response> {
+    match from_hex(input) {
+        Some(v) if v.len() == 32 => None,
+        _ => Some(Response::builder().status(400).body(Body::from("sha256 should be 64 hex characters")).unwrap()),
+    }
+}
+
+/// Tells whether a file with the content is already stored, without adding a reference.
+pub async fn head_by_hash(dedup: &DedupIndex, sha256: String) -> Response {
+    if let Some(res) = parse_sha256(&sha256) {
+        return res;
+    }
+
+    match dedup.get(&sha256) {
+        Some((_, refcount)) => Response::builder()
+            .status(200)
+            .header("X-Teleton-Refcount", refcount)
+            .body(Body::empty())
+            .unwrap(),
+        None => Response::builder().status(404).body(Body::empty()).unwrap(),
+    }
+}
+
+/// Adds a reference to the stored file with the content, so the client can skip uploading it.
+pub async fn claim_by_hash(client: &Client, dedup: &DedupIndex, sha256: String) -> Response {
+    if let Some(res) = parse_sha256(&sha256) {
+        return res;
+    }
+
+    let file_ref = match dedup.reuse(client, &sha256).await {
+        Some(v) => v,
+        None => {
+            return Response::builder().status(404).body(Body::from("file not found")).unwrap();
+        }
+    };
+
+    let res = UploadFinalizeResponse {
+        r#ref: file_ref.to_ref_string(),
+    };
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(&res).unwrap()))
+        .unwrap()
+}
@@ -1,40 +1,150 @@
-use axum::{body::Body, response::Response};
+use axum::{body::Body, http::HeaderMap, response::Response};
 use grammers_client::{Client, grammers_tl_types as tl};
 
-use crate::{proto::FileRef, shared::CHUNK_SIZE};
+use crate::{compression, config, crypto::{self, TAG_SIZE}, proto::{FileCompression, FileRef, FileRefV1}, transfers::TransferPool};
 
-use super::refresh_file_reference;
+use super::{file_etag, is_not_modified, not_modified, refresh_composite_file_ref, refresh_file_reference, with_cache_headers};
 
-pub async fn get_chunk(client: &Client, file_ref: String, offset: usize) -> Response {
+#[derive(serde::Deserialize)]
+pub struct GetChunkQueryParams {
+    /// `chunk_size` in the file meta if omitted
+    #[serde(default)]
+    chunk_size: Option<usize>,
+}
+
+pub async fn get_chunk(client: &Client, transfers: &TransferPool, file_ref: String, offset: usize, query: GetChunkQueryParams, headers: &HeaderMap) -> Response {
+    let (file_ref, chunk_size) = match parse_chunk_request(file_ref, offset, &query) {
+        Ok(v) => v,
+        Err(res) => return res,
+    };
+
+    // Last-Modified needs the message, which isn't worth another request per chunk
+    let etag = file_etag(&file_ref);
+    if is_not_modified(headers, etag.as_deref(), None) {
+        return not_modified(etag.as_deref(), None);
+    }
+
+    let bytes = match read_chunk(client, transfers, &file_ref, offset, chunk_size).await {
+        Ok(v) => v,
+        Err(res) => return res,
+    };
+
+    with_cache_headers(Response::builder(), etag.as_deref(), None)
+        .status(200)
+        .header("Content-Type", "application/octet-stream")
+        .header("Accept-Ranges", "none")
+        .body(Body::from(bytes))
+    .unwrap()
+}
+
+/// Same headers as `get_chunk`, with the length of the chunk computed from the ref instead of reading it.
+pub async fn head_chunk(file_ref: String, offset: usize, query: GetChunkQueryParams, headers: &HeaderMap) -> Response {
+    let (file_ref, chunk_size) = match parse_chunk_request(file_ref, offset, &query) {
+        Ok(v) => v,
+        Err(res) => return res,
+    };
+
+    let etag = file_etag(&file_ref);
+    if is_not_modified(headers, etag.as_deref(), None) {
+        return not_modified(etag.as_deref(), None);
+    }
+
+    let content_length = (file_ref.content_size().max(0) as usize).saturating_sub(offset).min(chunk_size);
+    with_cache_headers(Response::builder(), etag.as_deref(), None)
+        .status(200)
+        .header("Content-Type", "application/octet-stream")
+        .header("Accept-Ranges", "none")
+        .header("Content-Length", content_length)
+        .body(Body::empty())
+    .unwrap()
+}
+
+/// Returns the ref and the chunk size, after checking that `offset` is at the start of a chunk.
+fn parse_chunk_request(file_ref: String, offset: usize, query: &GetChunkQueryParams) -> Result<(FileRef, usize), Response> {
     let file_ref = FileRef::from_ref_string(file_ref);
     let file_ref = match file_ref {
         Some(v) => v,
         None => {
-            return Response::builder().status(404).body(Body::from("chunk not found")).unwrap();
+            return Err(Response::builder().status(404).body(Body::from("chunk not found")).unwrap());
         }
     };
-    let file_ref = match file_ref.v1 {
-        Some(v) => v,
-        None => {
-            return Response::builder().status(404).body(Body::from("chunk not found")).unwrap();
+
+    // offsets are of the content, which is smaller than the stored parts if encrypted
+    let chunk_size = match query.chunk_size {
+        None => file_ref.chunk_size(),
+        Some(v) if file_ref.accepts_chunk_size(v) => v,
+        Some(_) => {
+            return Err(Response::builder().status(400).body(Body::from(format!("chunk_size can't be used for this file, try {}", file_ref.chunk_size()))).unwrap());
         }
     };
+    if (offset % chunk_size) > 0 {
+        return Err(Response::builder().status(400).body(Body::from(format!("offset should be divisible by {}", chunk_size))).unwrap());
+    }
+
+    Ok((file_ref, chunk_size))
+}
 
-    if (offset % CHUNK_SIZE) > 0 {
-        return Response::builder().status(400).body(Body::from("offset should be divisible by 524288")).unwrap();
+/// Reads the chunk of the content at `offset` (divisible by `chunk_size`), which is decrypted and decompressed.
+pub(crate) async fn read_chunk(client: &Client, transfers: &TransferPool, file_ref: &FileRef, offset: usize, chunk_size: usize) -> Result<Vec<u8>, Response> {
+    let part = (offset / chunk_size) as u64;
+    let encryption = file_ref.encryption();
+    let compression = file_ref.v1.as_ref().and_then(|x| x.compression.as_ref());
+    if compression.is_some() && offset as i64 >= file_ref.content_size() {
+        return Err(Response::builder().status(416).body(Body::from(format!("offset {} is out of the file (file_size is {})", offset, file_ref.content_size()))).unwrap());
     }
 
+    // composite files are read from the segment which has the offset
+    let manifest_message_id = file_ref.composite_v1.as_ref().map(|x| x.manifest_message_id);
+    let (file_ref, offset) = match file_ref {
+        FileRef { v1: Some(v), .. } => (v.clone(), offset),
+        FileRef { composite_v1: Some(composite), .. } => match composite.locate(offset as u64) {
+            Some((segment, offset)) => (segment, offset as usize),
+            None => {
+                return Err(Response::builder().status(416).body(Body::from(format!("offset {} is out of the file (file_size is {})", offset, composite.file_size))).unwrap());
+            }
+        },
+        _ => {
+            return Err(Response::builder().status(404).body(Body::from("chunk not found")).unwrap());
+        }
+    };
+
+    let bytes = match compression {
+        Some(compression) => fetch_frame(client, transfers, &file_ref, manifest_message_id, compression, part).await,
+        None => {
+            let stored_chunk_size = match encryption {
+                Some(_) => chunk_size + TAG_SIZE,
+                None => chunk_size,
+            };
+            fetch_part(client, transfers, &file_ref, manifest_message_id, (offset / chunk_size * stored_chunk_size) as u64, stored_chunk_size).await
+        },
+    };
+    let bytes = bytes?;
+
+    match encryption {
+        Some(encryption) => match crypto::decrypt_part(encryption, part, &bytes) {
+            Some(v) => Ok(v),
+            None => {
+                println!("failed to decrypt part {} of {}", part, file_ref.message_id);
+                Err(Response::builder().status(500).body(Body::from("failed to decrypt the chunk")).unwrap())
+            }
+        },
+        None => Ok(bytes),
+    }
+}
+
+/// Reads `limit` bytes of the stored file. `limit` should be a power of two up to 1 MiB, and `offset` should be divisible by it.
+///
+/// Errors are already responses, such as 409 with the refreshed ref if file reference is expired.
+async fn fetch_part(client: &Client, transfers: &TransferPool, file_ref: &FileRefV1, manifest_message_id: Option<i32>, offset: u64, limit: usize) -> Result<Vec<u8>, Response> {
     let req = tl::functions::upload::GetFile {
         cdn_supported: false,
-        limit: CHUNK_SIZE as i32,
-        location: tl::enums::InputFileLocation::InputDocumentFileLocation(tl::types::InputDocumentFileLocation {
-            id: file_ref.document_id, access_hash: file_ref.access_hash, file_reference: file_ref.file_reference.clone(), thumb_size: "".to_string()
-        }),
+        limit: limit as i32,
+        location: file_ref.input_location(),
         precise: false,
         offset: offset as _,
     };
 
-    let res = client.invoke(&req).await;
+    let res = transfers.invoke(&req).await;
 
     let res = match res {
         Ok(v) => v,
@@ -42,16 +152,20 @@ pub async fn get_chunk(client: &Client, file_ref: String, offset: usize) -> Resp
             match &e {
                 grammers_client::InvocationError::Rpc(e) => {
                     if e.name == "FILE_REFERENCE_EXPIRED" {
-                        match refresh_file_reference(client, &file_ref).await {
+                        let new_ref = match manifest_message_id {
+                            Some(id) => refresh_composite_file_ref(client, file_ref.peer.as_ref(), id, file_ref.copy_id).await,
+                            None => refresh_file_reference(client, file_ref).await,
+                        };
+                        match new_ref {
                             None => {
-                                return Response::builder().status(404).body(Body::from("chunk not found")).unwrap();
+                                return Err(Response::builder().status(404).body(Body::from("chunk not found")).unwrap());
                             }
                             Some(v) => {
-                                return Response::builder()
+                                return Err(Response::builder()
                                     .status(409)
                                     .header("X-New-Ref", v)
                                     .body(Body::empty())
-                                .unwrap();
+                                .unwrap());
                             }
                         }
                     }
@@ -59,7 +173,7 @@ pub async fn get_chunk(client: &Client, file_ref: String, offset: usize) -> Resp
                 _ => {},
             };
             println!("failed to get file {:?}", e);
-            return Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap();
+            return Err(Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap());
         }
     };
 
@@ -67,13 +181,50 @@ pub async fn get_chunk(client: &Client, file_ref: String, offset: usize) -> Resp
         tl::enums::upload::File::File(file) => file,
         tl::enums::upload::File::CdnRedirect(file_cdn_redirect) => {
             println!("TODO: redirected to cdn {:?}", file_cdn_redirect);
-            return Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap();
+            return Err(Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap());
         },
     };
 
-    Response::builder()
-        .status(200)
-        .header("Content-Type", "application/octet-stream")
-        .body(Body::from(res.bytes))
-    .unwrap()
-}
\ No newline at end of file
+    Ok(res.bytes)
+}
+
+/// Reads `[start, end)` of the stored file, which may span several parts.
+async fn fetch_range(client: &Client, transfers: &TransferPool, file_ref: &FileRefV1, manifest_message_id: Option<i32>, start: u64, end: u64) -> Result<Vec<u8>, Response> {
+    let block_size = config::get().download_chunk_size;
+    let first = start / block_size as u64 * block_size as u64;
+    let mut data = vec![];
+    let mut offset = first;
+    while offset < end {
+        data.extend(fetch_part(client, transfers, file_ref, manifest_message_id, offset, block_size).await?);
+        offset += block_size as u64;
+    }
+
+    let from = (start - first) as usize;
+    let to = (end - first) as usize;
+    match data.get(from..to) {
+        Some(v) => Ok(v.to_vec()),
+        None => {
+            println!("range {}..{} is out of the stored file {}", start, end, file_ref.message_id);
+            Err(Response::builder().status(500).body(Body::from("stored file is broken")).unwrap())
+        }
+    }
+}
+
+/// Reads a frame of a compressed file with the seek table, and decompresses it.
+async fn fetch_frame(client: &Client, transfers: &TransferPool, file_ref: &FileRefV1, manifest_message_id: Option<i32>, compression: &FileCompression, frame: u64) -> Result<Vec<u8>, Response> {
+    let (start, end) = compression::seek_table_entries(compression, frame);
+    let entries = fetch_range(client, transfers, file_ref, manifest_message_id, start, end).await?;
+    let (start, end) = match compression::parse_seek_table_entries(&entries) {
+        Some(v) => v,
+        None => {
+            println!("seek table of {} is broken", file_ref.message_id);
+            return Err(Response::builder().status(500).body(Body::from("stored file is broken")).unwrap());
+        }
+    };
+
+    let compressed = fetch_range(client, transfers, file_ref, manifest_message_id, start, end).await?;
+    match compression::decompress_frame(compression, &compressed) {
+        Some(v) => Ok(v),
+        None => Err(Response::builder().status(500).body(Body::from("failed to decompress the chunk")).unwrap()),
+    }
+}
@@ -0,0 +1,313 @@
+use axum::{body::Body, response::Response};
+use grammers_client::{Client, grammers_tl_types as tl};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+use crate::{handlers::upload::{manifest_text, send_manifest, send_media, UploadFinalizeResponse}, proto::{CompositeFileRefV1, FileRef, FileRefV1, PeerKind, PeerRef}, shared::{get_composite_file_ref, get_message, message_file, FileCaption, MAX_CAPTION_LENGTH, MAX_MESSAGE_LENGTH}};
+
+/// How many of the latest dialogs are searched for a chat given by its ID, since users and channels need their access hash.
+const DIALOG_LOOKUP_LIMIT: i32 = 100;
+
+#[derive(serde::Deserialize)]
+pub struct CopyFileBody {
+    /// `me` (default), a username, or an ID in the Bot API style (`-100` followed by the ID for channels, `-` for basic groups)
+    #[serde(default)]
+    peer: Option<String>,
+    /// replaces the file name, only for files whose name is kept in the caption (not plain documents)
+    #[serde(default)]
+    name: Option<String>,
+    /// replaces the metadata
+    #[serde(default)]
+    metadata: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// Parts of the caption which a copy replaces. `None` keeps the current one.
+pub(crate) struct CaptionChanges {
+    pub name: Option<String>,
+    pub mime_type: Option<String>,
+    pub metadata: Option<Option<serde_json::Map<String, serde_json::Value>>>,
+}
+
+/// Sends the stored file again without uploading it, to another chat or with another name, and returns the ref of the copy.
+pub async fn copy_file(client: &Client, file_ref: String, body: CopyFileBody) -> Response {
+    let file_ref = match FileRef::from_ref_string(file_ref) {
+        Some(v) => v,
+        None => {
+            return Response::builder().status(404).body(Body::from("file not found")).unwrap();
+        }
+    };
+
+    // upstream keeps the file name of a document which is sent again, so only the caption would change
+    if body.name.is_some() && is_plain_document(&file_ref) {
+        return Response::builder().status(400).body(Body::from("name can't be changed for documents, since the document keeps its original name. Upload the file again with the new name")).unwrap();
+    }
+
+    let peer = match resolve_peer(client, body.peer.as_deref()).await {
+        Ok(v) => v,
+        Err(res) => return res,
+    };
+
+    let changes = CaptionChanges {
+        name: body.name,
+        mime_type: None,
+        metadata: body.metadata.map(Some),
+    };
+    let copied = match copy_stored(client, &file_ref, peer, changes, new_random_id()).await {
+        Ok(v) => v,
+        Err(res) => return res,
+    };
+
+    let res = UploadFinalizeResponse {
+        r#ref: copied.to_ref_string(),
+    };
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(&res).unwrap()))
+        .unwrap()
+}
+
+/// Whether the file is a document which has its name as the file name attribute. Photos, encrypted,
+/// compressed and composite files keep it in the caption.
+fn is_plain_document(file_ref: &FileRef) -> bool {
+    match &file_ref.v1 {
+        Some(v1) => v1.photo_size_type.is_empty() && v1.encryption.is_none() && v1.compression.is_none(),
+        None => false,
+    }
+}
+
+fn new_random_id() -> i64 {
+    StdRng::from_entropy().next_u64() as i64
+}
+
+fn input_document(id: i64, access_hash: i64, file_reference: Vec<u8>) -> tl::enums::InputMedia {
+    tl::enums::InputMedia::Document(tl::types::InputMediaDocument {
+        spoiler: false,
+        id: tl::enums::InputDocument::Document(tl::types::InputDocument { id, access_hash, file_reference }),
+        ttl_seconds: None,
+        query: None,
+    })
+}
+
+/// Sends the stored file again with the caption changed, and returns the ref of the copy.
+/// `random_id` is of the message, or the first one of a composite file.
+pub(crate) async fn copy_stored(client: &Client, file_ref: &FileRef, peer: Option<PeerRef>, changes: CaptionChanges, random_id: i64) -> Result<FileRef, Response> {
+    match file_ref {
+        FileRef { v1: Some(v1), .. } => copy_single(client, v1, peer, changes, random_id).await,
+        FileRef { composite_v1: Some(composite), .. } => copy_composite(client, composite, peer, changes, random_id).await,
+        _ => Err(Response::builder().status(404).body(Body::from("file not found")).unwrap()),
+    }
+}
+
+async fn copy_single(client: &Client, file_ref: &FileRefV1, peer: Option<PeerRef>, changes: CaptionChanges, random_id: i64) -> Result<FileRef, Response> {
+    // the message gives the latest file reference, which upstream checks even for our own files
+    let message = match get_message(client, file_ref.peer.as_ref(), file_ref.message_id).await {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            return Err(Response::builder().status(404).body(Body::from("file not found")).unwrap());
+        }
+        Err(e) => {
+            println!("failed to get message {:?}", e);
+            return Err(Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap());
+        }
+    };
+    let file = match message_file(&message) {
+        Some(v) if v.id == file_ref.document_id => v,
+        _ => {
+            return Err(Response::builder().status(404).body(Body::from("file not found")).unwrap());
+        }
+    };
+
+    let media = match file.photo_size_type {
+        Some(_) => tl::enums::InputMedia::Photo(tl::types::InputMediaPhoto {
+            spoiler: false,
+            id: tl::enums::InputPhoto::Photo(tl::types::InputPhoto { id: file.id, access_hash: file.access_hash, file_reference: file.file_reference }),
+            ttl_seconds: None,
+        }),
+        None => input_document(file.id, file.access_hash, file.file_reference),
+    };
+
+    // everything else in the caption (e.g. the file key) is what the copy needs too
+    let mut caption = FileCaption::parse(&message.message).unwrap_or_default();
+    if let Some(name) = changes.name {
+        caption.name = Some(name);
+    }
+    if let Some(mime_type) = changes.mime_type {
+        caption.mime_type = Some(mime_type);
+    }
+    if let Some(metadata) = changes.metadata {
+        caption.metadata = metadata;
+    }
+    caption.random_id = Some(random_id);
+    let caption = match caption.key {
+        Some(_) => caption.seal().to_text(),
+        None => caption.to_text(),
+    };
+    if caption.encode_utf16().count() > MAX_CAPTION_LENGTH {
+        return Err(Response::builder().status(413).body(Body::from(format!("metadata is too large (should fit in {} characters with other data)", MAX_CAPTION_LENGTH))).unwrap());
+    }
+
+    send_media(client, peer.as_ref(), media, caption, random_id, None).await
+}
+
+/// Copies every segment, and then sends a new manifest which points to the copies.
+async fn copy_composite(client: &Client, file_ref: &CompositeFileRefV1, peer: Option<PeerRef>, changes: CaptionChanges, random_id: i64) -> Result<FileRef, Response> {
+    let (message, current) = match get_composite_file_ref(client, file_ref.peer.as_ref(), file_ref.manifest_message_id).await {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            return Err(Response::builder().status(404).body(Body::from("file not found")).unwrap());
+        }
+        Err(e) => {
+            println!("failed to get manifest {:?}", e);
+            return Err(Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap());
+        }
+    };
+    let current = match current.composite_v1 {
+        Some(v) => v,
+        None => {
+            return Err(Response::builder().status(404).body(Body::from("file not found")).unwrap());
+        }
+    };
+
+    // get_composite_file_ref already checked that this is a manifest
+    let mut caption = FileCaption::parse(&message.message).unwrap();
+    // segments use random_id + index
+    let manifest_random_id = random_id.wrapping_add(current.segments.len() as i64);
+    caption.random_id = Some(manifest_random_id);
+    if let Some(metadata) = changes.metadata {
+        caption.metadata = metadata;
+    }
+    if let Some(manifest) = &mut caption.manifest {
+        if let Some(name) = changes.name {
+            manifest.name = name;
+        }
+        if let Some(mime_type) = changes.mime_type {
+            manifest.mime_type = mime_type;
+        }
+        // placeholders to check the length before sending anything
+        manifest.segments = vec![i32::MAX; current.segments.len()];
+    }
+    if manifest_text(&caption).encode_utf16().count() > MAX_MESSAGE_LENGTH {
+        return Err(Response::builder().status(413).body(Body::from(format!("metadata is too large (should fit in {} characters with other data)", MAX_MESSAGE_LENGTH))).unwrap());
+    }
+
+    let mut segment_refs = vec![];
+    for (i, segment) in current.segments.iter().enumerate() {
+        let segment_random_id = random_id.wrapping_add(i as i64);
+        let segment_caption = FileCaption {
+            segment: Some(i as u32),
+            random_id: Some(segment_random_id),
+            ..Default::default()
+        }.to_text();
+        let media = input_document(segment.document_id, segment.access_hash, segment.file_reference.clone());
+
+        let copied = send_media(client, peer.as_ref(), media, segment_caption, segment_random_id, None).await?;
+        match copied.v1 {
+            // the peer is on the composite ref
+            Some(v) => segment_refs.push(FileRefV1 { peer: None, ..v }),
+            None => {
+                return Err(Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap());
+            }
+        }
+    }
+
+    if let Some(manifest) = &mut caption.manifest {
+        manifest.segments = segment_refs.iter().map(|x| x.message_id).collect();
+    }
+    let manifest_message_id = send_manifest(client, peer.as_ref(), &caption, manifest_random_id).await?;
+
+    Ok(FileRef {
+        composite_v1: Some(CompositeFileRefV1 {
+            manifest_message_id,
+            segments: segment_refs,
+            peer,
+            ..current
+        }),
+        ..Default::default()
+    })
+}
+
+/// Returns `None` for Saved Messages.
+async fn resolve_peer(client: &Client, input: Option<&str>) -> Result<Option<PeerRef>, Response> {
+    let input = match input {
+        None | Some("") | Some("me") | Some("self") => return Ok(None),
+        Some(v) => v,
+    };
+
+    let peer = match input.parse::<i64>() {
+        Ok(id) => resolve_peer_id(client, id).await?,
+        Err(_) => resolve_username(client, input.trim_start_matches('@')).await?,
+    };
+    match peer {
+        Some(v) => Ok(Some(v)),
+        None => Err(Response::builder().status(404).body(Body::from("peer not found")).unwrap()),
+    }
+}
+
+async fn resolve_username(client: &Client, username: &str) -> Result<Option<PeerRef>, Response> {
+    let req = tl::functions::contacts::ResolveUsername {
+        username: username.to_string(),
+    };
+    let res = match client.invoke(&req).await {
+        Ok(tl::enums::contacts::ResolvedPeer::Peer(v)) => v,
+        Err(grammers_client::InvocationError::Rpc(e)) if e.name == "USERNAME_NOT_OCCUPIED" || e.name == "USERNAME_INVALID" => return Ok(None),
+        Err(e) => {
+            println!("failed to resolve username {:?}", e);
+            return Err(Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap());
+        }
+    };
+
+    Ok(find_peer(&res.peer, &res.chats, &res.users))
+}
+
+/// Looks up the chat among the latest dialogs, since only they tell the access hash of an ID.
+async fn resolve_peer_id(client: &Client, id: i64) -> Result<Option<PeerRef>, Response> {
+    let peer = match id {
+        // -100 followed by the channel ID
+        ..=-1_000_000_000_000 => match id.checked_neg().and_then(|x| x.checked_sub(1_000_000_000_000)) {
+            Some(channel_id) => tl::enums::Peer::Channel(tl::types::PeerChannel { channel_id }),
+            None => return Err(Response::builder().status(400).body(Body::from("invalid peer")).unwrap()),
+        },
+        // basic groups don't need the access hash
+        ..=-1 => return Ok(Some(PeerRef { kind: PeerKind::Chat as i32, id: -id, access_hash: 0 })),
+        _ => tl::enums::Peer::User(tl::types::PeerUser { user_id: id }),
+    };
+
+    let req = tl::functions::messages::GetDialogs {
+        exclude_pinned: false,
+        folder_id: None,
+        offset_date: 0,
+        offset_id: 0,
+        offset_peer: tl::enums::InputPeer::Empty,
+        limit: DIALOG_LOOKUP_LIMIT,
+        hash: 0,
+    };
+    let (chats, users) = match client.invoke(&req).await {
+        Ok(tl::enums::messages::Dialogs::Dialogs(v)) => (v.chats, v.users),
+        Ok(tl::enums::messages::Dialogs::Slice(v)) => (v.chats, v.users),
+        Ok(v) => {
+            println!("not expected dialogs {:?}", v);
+            return Err(Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap());
+        }
+        Err(e) => {
+            println!("failed to get dialogs {:?}", e);
+            return Err(Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap());
+        }
+    };
+
+    Ok(find_peer(&peer, &chats, &users))
+}
+
+fn find_peer(peer: &tl::enums::Peer, chats: &[tl::enums::Chat], users: &[tl::enums::User]) -> Option<PeerRef> {
+    match peer {
+        tl::enums::Peer::User(p) => users.iter().find_map(|x| match x {
+            tl::enums::User::User(user) if user.id == p.user_id => Some(PeerRef { kind: PeerKind::User as i32, id: user.id, access_hash: user.access_hash? }),
+            _ => None,
+        }),
+        tl::enums::Peer::Chat(p) => Some(PeerRef { kind: PeerKind::Chat as i32, id: p.chat_id, access_hash: 0 }),
+        tl::enums::Peer::Channel(p) => chats.iter().find_map(|x| match x {
+            tl::enums::Chat::Channel(channel) if channel.id == p.channel_id => Some(PeerRef { kind: PeerKind::Channel as i32, id: channel.id, access_hash: channel.access_hash? }),
+            _ => None,
+        }),
+    }
+}
@@ -0,0 +1,45 @@
+use axum::{body::Body, response::Response};
+use grammers_client::{Client, grammers_tl_types as tl};
+
+use crate::{dedup::DedupIndex, proto::{FileRef, PeerRef}, shared::to_hex};
+
+/// Deletes a logical copy of the file. Messages are deleted only when no other copy shares them.
+pub async fn delete_file(client: &Client, dedup: &DedupIndex, file_ref: String) -> Response {
+    let file_ref = match FileRef::from_ref_string(file_ref) {
+        Some(v) => v,
+        None => {
+            return Response::builder().status(404).body(Body::from("file not found")).unwrap();
+        }
+    };
+    let message_ids = file_ref.message_ids();
+    if message_ids.is_empty() {
+        return Response::builder().status(404).body(Body::from("file not found")).unwrap();
+    }
+
+    let sha256 = to_hex(file_ref.sha256());
+    let indexed = match dedup.get(&sha256) {
+        // the index may point to another message with the same content (e.g. uploaded concurrently), and copies in other chats are not indexed
+        Some((r#ref, _)) if file_ref.peer().is_none() => FileRef::from_ref_string(r#ref).map(|x| x.message_ids()) == Some(message_ids.clone()),
+        _ => false,
+    };
+    if indexed {
+        match dedup.release(&sha256, file_ref.copy_id()) {
+            Some(left) if left > 0 => {
+                println!("file {} still has {} references, keeping it", sha256, left);
+                return Response::builder().status(204).body(Body::empty()).unwrap();
+            },
+            _ => {},
+        }
+    }
+
+    let res = match PeerRef::input_channel(file_ref.peer()) {
+        Some(channel) => client.invoke(&tl::functions::channels::DeleteMessages { channel, id: message_ids }).await,
+        None => client.invoke(&tl::functions::messages::DeleteMessages { revoke: true, id: message_ids }).await,
+    };
+    if let Err(e) = res {
+        println!("failed to delete messages {:?}", e);
+        return Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap();
+    }
+
+    Response::builder().status(204).body(Body::empty()).unwrap()
+}
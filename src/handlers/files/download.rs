@@ -0,0 +1,266 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use axum::{body::Body, http::{response::Builder, HeaderMap}, response::Response};
+use grammers_client::Client;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+use crate::{proto::FileRef, shared::{is_valid_content_type, to_hex}, transfers::TransferPool};
+
+use super::{chunk::read_chunk, file_etag, get_stored_file, is_not_modified, is_range_fresh, not_modified, with_cache_headers};
+
+/// More ranges than this in a request are ignored, and the whole file is sent instead.
+const MAX_RANGES: usize = 16;
+
+enum Piece {
+    Bytes(Vec<u8>),
+    /// `start..end` of the content
+    Range(u64, u64),
+}
+
+/// The response of a file without its body: the headers, and what the body consists of.
+struct PreparedFile {
+    builder: Builder,
+    pieces: VecDeque<Piece>,
+    /// has the latest file reference
+    file_ref: FileRef,
+}
+
+/// Sends the whole file, or the requested byte ranges of it, so that players and download managers can use it directly.
+pub async fn download_file(client: &Client, transfers: &Arc<TransferPool>, file_ref: String, headers: &HeaderMap) -> Response {
+    let prepared = match prepare_file(client, file_ref, headers).await {
+        Ok(v) => v,
+        Err(res) => return res,
+    };
+
+    let chunk_size = prepared.file_ref.chunk_size();
+    let state = (prepared.pieces, client.clone(), transfers.clone(), prepared.file_ref);
+    // each step reads one aligned chunk, and trims it to the range
+    let stream = futures_util::stream::unfold(state, move |(mut pieces, client, transfers, file_ref)| async move {
+        let (start, end) = match pieces.pop_front()? {
+            Piece::Bytes(bytes) => return Some((Ok(bytes), (pieces, client, transfers, file_ref))),
+            Piece::Range(start, end) => (start, end),
+        };
+        let aligned = start / chunk_size as u64 * chunk_size as u64;
+        let bytes = match read_chunk(&client, &transfers, &file_ref, aligned as usize, chunk_size).await {
+            Ok(v) => v,
+            Err(res) => {
+                println!("failed to read chunk at {} for download ({})", aligned, res.status());
+                let e = std::io::Error::new(std::io::ErrorKind::Other, "failed to read the chunk");
+                return Some((Err(e), (VecDeque::new(), client, transfers, file_ref)));
+            }
+        };
+
+        let chunk_end = aligned + bytes.len() as u64;
+        if chunk_end <= start {
+            println!("chunk at {} is shorter than expected", aligned);
+            let e = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "the file is shorter than expected");
+            return Some((Err(e), (VecDeque::new(), client, transfers, file_ref)));
+        }
+        if chunk_end < end {
+            pieces.push_front(Piece::Range(chunk_end, end));
+        }
+        let bytes = bytes[(start - aligned) as usize..(end.min(chunk_end) - aligned) as usize].to_vec();
+        Some((Ok(bytes), (pieces, client, transfers, file_ref)))
+    });
+
+    prepared.builder
+        .body(Body::from_stream(stream))
+        .unwrap()
+}
+
+/// Same headers as `download_file`. Only the message is fetched from upstream, not the content.
+pub async fn head_file(client: &Client, file_ref: String, headers: &HeaderMap) -> Response {
+    match prepare_file(client, file_ref, headers).await {
+        Ok(v) => v.builder.body(Body::empty()).unwrap(),
+        Err(res) => res,
+    }
+}
+
+/// Builds the headers from the message of the file. Responses without a body (e.g. 304) are returned as `Err`.
+async fn prepare_file(client: &Client, file_ref: String, headers: &HeaderMap) -> Result<PreparedFile, Response> {
+    let file_ref = match FileRef::from_ref_string(file_ref) {
+        Some(v) => v,
+        None => {
+            return Err(Response::builder().status(404).body(Body::from("file not found")).unwrap());
+        }
+    };
+
+    // the ETag doesn't need upstream, so revalidating a cached file costs nothing
+    let etag = file_etag(&file_ref);
+    if headers.contains_key("If-None-Match") && is_not_modified(headers, etag.as_deref(), None) {
+        return Err(not_modified(etag.as_deref(), None));
+    }
+
+    // the message has the latest file reference, so that the body doesn't fail halfway with 409
+    let stored = get_stored_file(client, &file_ref).await?;
+    let file_size = stored.file_ref.content_size() as u64;
+    if is_not_modified(headers, etag.as_deref(), Some(stored.mtime)) {
+        return Err(not_modified(etag.as_deref(), Some(stored.mtime)));
+    }
+
+    let ranges = headers.get("Range")
+        .and_then(|x| x.to_str().ok())
+        .filter(|_| is_range_fresh(headers, etag.as_deref(), stored.mtime))
+        .and_then(|x| parse_ranges(x, file_size));
+
+    // may be unusable if it was stored before content types were checked
+    let content_type = match is_valid_content_type(&stored.mime_type) {
+        true => stored.mime_type.as_str(),
+        false => "application/octet-stream",
+    };
+
+    let mut builder = with_cache_headers(Response::builder(), etag.as_deref(), Some(stored.mtime))
+        .header("Accept-Ranges", "bytes");
+    if let Some(name) = &stored.name {
+        builder = builder.header("Content-Disposition", format!("inline; filename*=UTF-8''{}", encode_filename(name)));
+    }
+
+    let (pieces, content_length) = match ranges.as_deref() {
+        None => {
+            builder = builder
+                .status(200)
+                .header("Content-Type", content_type);
+            (VecDeque::from([Piece::Range(0, file_size)]), file_size)
+        },
+        Some([]) => {
+            return Err(Response::builder()
+                .status(416)
+                .header("Content-Range", format!("bytes */{}", file_size))
+                .body(Body::from(format!("range is out of the file (file_size is {})", file_size)))
+                .unwrap());
+        },
+        Some(&[(start, end)]) => {
+            builder = builder
+                .status(206)
+                .header("Content-Type", content_type)
+                .header("Content-Range", format!("bytes {}-{}/{}", start, end - 1, file_size));
+            (VecDeque::from([Piece::Range(start, end)]), end - start)
+        },
+        Some(ranges) => {
+            let boundary = to_hex(&StdRng::from_entropy().next_u64().to_be_bytes());
+            let mut pieces = VecDeque::new();
+            let mut content_length = 0;
+            for &(start, end) in ranges {
+                let part_header = format!("\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n", boundary, content_type, start, end - 1, file_size);
+                content_length += part_header.len() as u64 + (end - start);
+                pieces.push_back(Piece::Bytes(part_header.into_bytes()));
+                pieces.push_back(Piece::Range(start, end));
+            }
+            let closing = format!("\r\n--{}--\r\n", boundary);
+            content_length += closing.len() as u64;
+            pieces.push_back(Piece::Bytes(closing.into_bytes()));
+
+            builder = builder
+                .status(206)
+                .header("Content-Type", format!("multipart/byteranges; boundary={}", boundary));
+            (pieces, content_length)
+        },
+    };
+
+    Ok(PreparedFile {
+        builder: builder.header("Content-Length", content_length),
+        pieces,
+        file_ref: stored.file_ref,
+    })
+}
+
+/// Returns `start..end` of every satisfiable range, or `None` if the header should be ignored.
+fn parse_ranges(header: &str, file_size: u64) -> Option<Vec<(u64, u64)>> {
+    let specs = header.trim().strip_prefix("bytes=")?;
+    let specs: Vec<&str> = specs.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()).collect();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return None;
+    }
+
+    let mut ranges = vec![];
+    for spec in specs {
+        let (first, last) = spec.split_once('-')?;
+        let range = match (first.trim(), last.trim()) {
+            // the last N bytes
+            ("", suffix) => {
+                let suffix = suffix.parse::<u64>().ok()?;
+                if suffix == 0 {
+                    None
+                } else {
+                    Some((file_size.saturating_sub(suffix), file_size))
+                }
+            },
+            (first, last) => {
+                let first = first.parse::<u64>().ok()?;
+                let end = match last {
+                    "" => file_size,
+                    last => {
+                        let last = last.parse::<u64>().ok()?;
+                        if last < first {
+                            return None;
+                        }
+                        last.saturating_add(1).min(file_size)
+                    },
+                };
+                Some((first, end))
+            },
+        };
+        if let Some((start, end)) = range {
+            if start < end {
+                ranges.push((start, end));
+            }
+        }
+    }
+
+    Some(ranges)
+}
+
+/// Percent-encodes the name for `filename*` of `Content-Disposition`.
+fn encode_filename(name: &str) -> String {
+    let mut encoded = String::new();
+    for b in name.bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(parse_ranges("bytes=0-99", 1000), Some(vec![(0, 100)]));
+        assert_eq!(parse_ranges("bytes=900-", 1000), Some(vec![(900, 1000)]));
+        assert_eq!(parse_ranges("bytes=-100", 1000), Some(vec![(900, 1000)]));
+        assert_eq!(parse_ranges("bytes=-5000", 1000), Some(vec![(0, 1000)]));
+        assert_eq!(parse_ranges("bytes=500-5000", 1000), Some(vec![(500, 1000)]));
+        assert_eq!(parse_ranges("bytes=0-18446744073709551615", 1000), Some(vec![(0, 1000)]));
+    }
+
+    #[test]
+    fn multiple_ranges() {
+        assert_eq!(parse_ranges("bytes=0-9, 20-29,-10", 1000), Some(vec![(0, 10), (20, 30), (990, 1000)]));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(parse_ranges("bytes=1000-", 1000), Some(vec![]));
+        assert_eq!(parse_ranges("bytes=-0", 1000), Some(vec![]));
+        assert_eq!(parse_ranges("bytes=2000-3000,-0", 1000), Some(vec![]));
+        assert_eq!(parse_ranges("bytes=0-", 0), Some(vec![]));
+    }
+
+    #[test]
+    fn ignored_headers() {
+        assert_eq!(parse_ranges("items=0-99", 1000), None);
+        assert_eq!(parse_ranges("bytes=", 1000), None);
+        assert_eq!(parse_ranges("bytes=99-0", 1000), None);
+        assert_eq!(parse_ranges("bytes=a-b", 1000), None);
+        assert_eq!(parse_ranges("bytes=0", 1000), None);
+
+        let specs = (0..=MAX_RANGES).map(|i| format!("{}-{}", i * 10, i * 10 + 1)).collect::<Vec<_>>();
+        assert_eq!(parse_ranges(&format!("bytes={}", specs.join(",")), 1000), None);
+        let specs = &specs[..MAX_RANGES];
+        assert_eq!(parse_ranges(&format!("bytes={}", specs.join(",")), 1000).map(|x| x.len()), Some(MAX_RANGES));
+    }
+}
@@ -0,0 +1,153 @@
+use axum::{body::Body, response::Response};
+use grammers_client::{grammers_tl_types as tl, Client};
+
+use crate::shared::{get_messages, manifest_to_file_ref, message_file, message_to_file_ref, FileCaption};
+
+const DEFAULT_LIMIT: i32 = 50;
+const MAX_LIMIT: i32 = 100;
+
+#[derive(serde::Deserialize)]
+pub struct ListFilesQueryParams {
+    /// returns files older than this message id
+    #[serde(default)]
+    offset_id: i32,
+    #[serde(default)]
+    limit: Option<i32>,
+}
+
+#[derive(serde::Serialize)]
+struct FileListItem {
+    r#ref: String,
+    file_size: i64,
+    mtime: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+#[derive(serde::Serialize)]
+struct ListFilesResponse {
+    files: Vec<FileListItem>,
+    /// pass this as `offset_id` to get the next page, absent on the last page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_offset_id: Option<i32>,
+}
+
+/// Lists stored files, newest first.
+pub async fn list_files(client: &Client, query: ListFilesQueryParams) -> Response {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let req = tl::functions::messages::GetHistory {
+        peer: tl::enums::InputPeer::PeerSelf,
+        offset_id: query.offset_id,
+        offset_date: 0,
+        add_offset: 0,
+        limit,
+        max_id: 0,
+        min_id: 0,
+        hash: 0,
+    };
+
+    let res = match client.invoke(&req).await {
+        Ok(v) => v,
+        Err(e) => {
+            println!("failed to get history {:?}", e);
+            return Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap();
+        }
+    };
+
+    let messages = match res {
+        tl::enums::messages::Messages::Messages(m) => m.messages,
+        tl::enums::messages::Messages::Slice(m) => m.messages,
+        _ => {
+            println!("not expected messages {:?}", res);
+            return Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap();
+        }
+    };
+
+    let next_offset_id = if messages.len() < limit as usize {
+        None
+    } else {
+        messages.iter().map(|x| match x {
+            tl::enums::Message::Empty(m) => m.id,
+            tl::enums::Message::Message(m) => m.id,
+            tl::enums::Message::Service(m) => m.id,
+        }).min()
+    };
+
+    let messages: Vec<_> = messages.into_iter().filter_map(|x| match x {
+        tl::enums::Message::Message(m) => Some(m),
+        _ => None,
+    }).collect();
+
+    // composite files need their segments to build refs
+    let segment_ids: Vec<i32> = messages.iter()
+        .filter_map(|x| FileCaption::parse(&x.message)?.manifest)
+        .flat_map(|x| x.segments)
+        .collect();
+    let segments = if segment_ids.is_empty() {
+        vec![]
+    } else {
+        match get_messages(client, None, &segment_ids).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("failed to get segments {:?}", e);
+                return Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap();
+            }
+        }
+    };
+
+    let files = messages.iter().filter_map(|message| {
+        let caption = FileCaption::parse(&message.message);
+        if let Some(caption) = caption {
+            if caption.segment.is_some() {
+                // a part of a composite file, which is listed with its manifest
+                return None;
+            }
+            if let Some(manifest) = caption.manifest {
+                let file_ref = manifest_to_file_ref(message, &segments)?;
+                return Some(FileListItem {
+                    r#ref: file_ref.to_ref_string(),
+                    file_size: manifest.file_size,
+                    mtime: message.date,
+                    sha256: caption.sha256,
+                    name: Some(manifest.name),
+                    mime_type: manifest.mime_type,
+                    metadata: caption.metadata,
+                });
+            }
+        }
+        if !matches!(message.media, Some(tl::enums::MessageMedia::Document(_) | tl::enums::MessageMedia::Photo(_))) {
+            return None;
+        }
+        let file = message_file(message)?;
+        let file_ref = message_to_file_ref(message)?;
+        let caption = FileCaption::parse(&message.message);
+
+        Some(FileListItem {
+            r#ref: file_ref.to_ref_string(),
+            file_size: file_ref.content_size(),
+            mtime: file.date,
+            sha256: caption.as_ref().and_then(|x| x.sha256.clone()),
+            name: file.name,
+            mime_type: file.mime_type,
+            metadata: caption.and_then(|x| x.metadata),
+        })
+    }).collect();
+
+    let res = ListFilesResponse {
+        files,
+        next_offset_id,
+    };
+    let res = serde_json::to_vec(&res).unwrap();
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(res))
+        .unwrap()
+}
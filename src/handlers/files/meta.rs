@@ -1,14 +1,29 @@
 use axum::{body::Body, response::Response};
-use grammers_client::{grammers_tl_types as tl, Client};
+use grammers_client::Client;
 
-use crate::proto::FileRef;
-
-use super::refresh_file_reference;
+use crate::{compression, proto::{CompositeFileRefV1, FileRef}, shared::{get_composite_file_ref, get_message, message_file, message_to_file_ref, to_hex, FileCaption}};
 
 #[derive(serde::Serialize)]
 struct FileMetaResponse {
     file_size: i64,
+    /// offsets of the chunk API should be divisible by this
+    chunk_size: usize,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    encrypted: bool,
+    /// only for compressed files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<&'static str>,
+    /// size in upstream, only for compressed files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stored_size: Option<i64>,
     mtime: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Map<String, serde_json::Value>>,
 }
 
 pub async fn get_file_meta(client: &Client, file_ref: String) -> Response {
@@ -19,6 +34,10 @@ pub async fn get_file_meta(client: &Client, file_ref: String) -> Response {
             return Response::builder().status(404).body(Body::from("chunk not found")).unwrap();
         }
     };
+    let chunk_size = file_ref.chunk_size();
+    if let Some(composite) = file_ref.composite_v1 {
+        return get_composite_file_meta(client, composite).await;
+    }
     let file_ref = match file_ref.v1 {
         Some(v) => v,
         None => {
@@ -26,56 +45,103 @@ pub async fn get_file_meta(client: &Client, file_ref: String) -> Response {
         }
     };
 
-    let req = tl::functions::upload::GetFile {
-        cdn_supported: false,
-        limit: 4096,
-        location: tl::enums::InputFileLocation::InputDocumentFileLocation(tl::types::InputDocumentFileLocation {
-            id: file_ref.document_id, access_hash: file_ref.access_hash, file_reference: file_ref.file_reference.clone(), thumb_size: "".to_string()
-        }),
-        precise: false,
-        offset: 0,
+    // the message has everything we need, and unlike GetFile it doesn't care about file_reference
+    let message = match get_message(client, file_ref.peer.as_ref(), file_ref.message_id).await {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            return Response::builder().status(404).body(Body::from("chunk not found")).unwrap();
+        }
+        Err(e) => {
+            println!("failed to get message {:?}", e);
+            return Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap();
+        }
     };
 
-    let res = client.invoke(&req).await;
+    let file = match message_file(&message) {
+        Some(v) if v.id == file_ref.document_id => v,
+        _ => {
+            return Response::builder().status(404).body(Body::from("chunk not found")).unwrap();
+        }
+    };
+
+    // same as the chunk API, clients expect 409 to update the ref
+    if file.file_reference != file_ref.file_reference {
+        let mut new_ref = match message_to_file_ref(&message) {
+            Some(v) => v,
+            None => {
+                return Response::builder().status(404).body(Body::from("chunk not found")).unwrap();
+            }
+        };
+        new_ref.set_peer(file_ref.peer.clone());
+        new_ref.set_copy_id(file_ref.copy_id);
+        return Response::builder()
+            .status(409)
+            .header("X-New-Ref", new_ref.to_ref_string())
+            .body(Body::empty())
+        .unwrap();
+    }
 
-    let res = match res {
-        Ok(v) => v,
+    let res = FileMetaResponse {
+        file_size: file_ref.content_size(),
+        chunk_size,
+        encrypted: file_ref.encryption.is_some(),
+        compression: file_ref.compression.as_ref().map(compression::compression_name),
+        stored_size: file_ref.compression.as_ref().map(|_| file_ref.file_size),
+        mtime: file.date,
+        sha256: if file_ref.sha256.is_empty() { None } else { Some(to_hex(&file_ref.sha256)) },
+        name: file.name,
+        mime_type: file.mime_type,
+        metadata: FileCaption::parse(&message.message).and_then(|x| x.metadata),
+    };
+    let res = serde_json::to_vec(&res).unwrap();
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(res))
+        .unwrap()
+}
+
+async fn get_composite_file_meta(client: &Client, file_ref: CompositeFileRefV1) -> Response {
+    let (message, current) = match get_composite_file_ref(client, file_ref.peer.as_ref(), file_ref.manifest_message_id).await {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            return Response::builder().status(404).body(Body::from("chunk not found")).unwrap();
+        }
         Err(e) => {
-            match &e {
-                grammers_client::InvocationError::Rpc(e) => {
-                    if e.name == "FILE_REFERENCE_EXPIRED" {
-                        match refresh_file_reference(client, &file_ref).await {
-                            None => {
-                                return Response::builder().status(404).body(Body::from("chunk not found")).unwrap();
-                            }
-                            Some(v) => {
-                                return Response::builder()
-                                    .status(409)
-                                    .header("X-New-Ref", v)
-                                    .body(Body::empty())
-                                .unwrap();
-                            }
-                        }
-                    }
-                },
-                _ => {},
-            };
-            println!("failed to get file {:?}", e);
+            println!("failed to get manifest {:?}", e);
             return Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap();
         }
     };
-
-    let res = match res {
-        tl::enums::upload::File::File(file) => file,
-        tl::enums::upload::File::CdnRedirect(file_cdn_redirect) => {
-            println!("TODO: redirected to cdn {:?}", file_cdn_redirect);
-            return Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap();
-        },
+    let stale = match &current.composite_v1 {
+        Some(current) => current.segments.iter().zip(&file_ref.segments).any(|(a, b)| a.file_reference != b.file_reference),
+        None => false,
     };
+    if stale {
+        let mut new_ref = current.clone();
+        new_ref.set_copy_id(file_ref.copy_id);
+        return Response::builder()
+            .status(409)
+            .header("X-New-Ref", new_ref.to_ref_string())
+            .body(Body::empty())
+        .unwrap();
+    }
+
+    // get_composite_file_ref already checked that this is a manifest
+    let caption = FileCaption::parse(&message.message).unwrap();
+    let manifest = caption.manifest.unwrap();
 
     let res = FileMetaResponse {
-        file_size: file_ref.file_size,
-        mtime: res.mtime,
+        file_size: manifest.file_size,
+        chunk_size: current.chunk_size(),
+        encrypted: current.encryption().is_some(),
+        compression: None,
+        stored_size: None,
+        mtime: message.date,
+        sha256: caption.sha256,
+        name: Some(manifest.name),
+        mime_type: manifest.mime_type,
+        metadata: caption.metadata,
     };
     let res = serde_json::to_vec(&res).unwrap();
 
@@ -83,5 +149,5 @@ pub async fn get_file_meta(client: &Client, file_ref: String) -> Response {
         .status(200)
         .header("Content-Type", "application/json")
         .body(Body::from(res))
-    .unwrap()
+        .unwrap()
 }
@@ -1,46 +1,236 @@
-use grammers_client::{Client, grammers_tl_types as tl};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::{proto::FileRefV1, shared::message_to_file_ref};
+use axum::{body::Body, http::{response::Builder, HeaderMap}, response::Response};
+use grammers_client::Client;
+
+use crate::{proto::{FileRef, FileRefV1, PeerRef}, shared::{get_composite_file_ref, get_message, message_file, message_to_file_ref, FileCaption}};
 
 pub mod chunk;
 pub mod meta;
+pub mod list;
+pub mod by_hash;
+pub mod delete;
+pub mod copy;
+pub mod thumbnail;
+pub mod download;
 
-pub async fn refresh_file_reference(client: &Client, file_ref: &FileRefV1) -> Option<String> {
-    let req = tl::functions::messages::GetMessages {
-        id: vec![tl::enums::InputMessage::Id(tl::types::InputMessageId {
-            id: file_ref.message_id,
-        })]
-    };
-    let res = client.invoke(&req).await;
+/// Content behind a ref never changes, since another upload gets another ref.
+const CACHE_CONTROL: &str = "private, max-age=31536000, immutable";
+
+/// What the message of a stored file tells, with the ref which has the latest file reference.
+pub(crate) struct StoredFile {
+    pub file_ref: FileRef,
+    pub name: Option<String>,
+    pub mime_type: String,
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    pub mtime: i32,
+}
+
+pub(crate) async fn get_stored_file(client: &Client, file_ref: &FileRef) -> Result<StoredFile, Response> {
+    match file_ref {
+        FileRef { v1: Some(v1), .. } => {
+            let message = match get_message(client, v1.peer.as_ref(), v1.message_id).await {
+                Ok(Some(v)) => v,
+                Ok(None) => {
+                    return Err(Response::builder().status(404).body(Body::from("file not found")).unwrap());
+                }
+                Err(e) => {
+                    println!("failed to get message {:?}", e);
+                    return Err(Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap());
+                }
+            };
+            let file = match message_file(&message) {
+                Some(v) if v.id == v1.document_id => v,
+                _ => {
+                    return Err(Response::builder().status(404).body(Body::from("file not found")).unwrap());
+                }
+            };
+
+            Ok(StoredFile {
+                file_ref: FileRef {
+                    v1: Some(FileRefV1 { file_reference: file.file_reference, ..v1.clone() }),
+                    ..Default::default()
+                },
+                name: file.name,
+                mime_type: file.mime_type,
+                metadata: FileCaption::parse(&message.message).and_then(|x| x.metadata),
+                mtime: file.date,
+            })
+        },
+        FileRef { composite_v1: Some(composite), .. } => {
+            let (message, current) = match get_composite_file_ref(client, composite.peer.as_ref(), composite.manifest_message_id).await {
+                Ok(Some(v)) => v,
+                Ok(None) => {
+                    return Err(Response::builder().status(404).body(Body::from("file not found")).unwrap());
+                }
+                Err(e) => {
+                    println!("failed to get manifest {:?}", e);
+                    return Err(Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap());
+                }
+            };
+            // get_composite_file_ref already checked that this is a manifest
+            let caption = FileCaption::parse(&message.message).unwrap();
+            let manifest = caption.manifest.unwrap();
+
+            Ok(StoredFile {
+                file_ref: current,
+                name: Some(manifest.name),
+                mime_type: manifest.mime_type,
+                metadata: caption.metadata,
+                mtime: message.date,
+            })
+        },
+        _ => Err(Response::builder().status(404).body(Body::from("file not found")).unwrap()),
+    }
+}
 
-    let res = match res {
+pub async fn refresh_file_reference(client: &Client, file_ref: &FileRefV1) -> Option<String> {
+    let res = match get_message(client, file_ref.peer.as_ref(), file_ref.message_id).await {
         Err(e) => {
             println!("failed to get message {:?}", e);
             return None;
         }
-        Ok(v) => v,
+        Ok(None) => return None,
+        Ok(Some(v)) => v,
     };
 
-    let res = match res {
-        tl::enums::messages::Messages::Messages(m) => m,
-        _ => {
-            println!("not expected messages {:?}", res);
-            return None;
+    let peer = file_ref.peer.clone();
+    let copy_id = file_ref.copy_id;
+    let file_ref = message_to_file_ref(&res);
+
+    return file_ref.map(|mut x| {
+        x.set_peer(peer);
+        x.set_copy_id(copy_id);
+        x.to_ref_string()
+    });
+}
+
+pub async fn refresh_composite_file_ref(client: &Client, peer: Option<&PeerRef>, manifest_message_id: i32, copy_id: u64) -> Option<String> {
+    match get_composite_file_ref(client, peer, manifest_message_id).await {
+        Err(e) => {
+            println!("failed to get manifest {:?}", e);
+            None
         }
-    };
+        Ok(v) => v.map(|(_, mut x)| {
+            x.set_copy_id(copy_id);
+            x.to_ref_string()
+        }),
+    }
+}
 
-    let res = &res.messages[0];
+/// Strong ETag from the document id and the message. Copies of a document have their own message, since their name
+/// or metadata (and so the headers) can differ, while refs which share the message (e.g. deduplicated) have the same one.
+pub(crate) fn file_etag(file_ref: &FileRef) -> Option<String> {
+    match file_ref {
+        FileRef { v1: Some(v1), .. } => Some(format!("\"{:x}-{}\"", v1.document_id as u64, message_tag(v1.peer.as_ref(), v1.message_id))),
+        // segments are not shared with other files, so the first one identifies the content
+        FileRef { composite_v1: Some(composite), .. } => composite.segments.first().map(|x| format!("\"{:x}-{}-{}\"", x.document_id as u64, composite.segments.len(), message_tag(composite.peer.as_ref(), composite.manifest_message_id))),
+        _ => None,
+    }
+}
 
-    let res = match res {
-        tl::enums::Message::Empty(message_empty) => {
-            println!("message not found {:?}", message_empty);
-            return None;
-        },
-        tl::enums::Message::Message(message) => message,
-        tl::enums::Message::Service(message_service) => todo!(),
+/// Message ids are only unique in a chat.
+fn message_tag(peer: Option<&PeerRef>, message_id: i32) -> String {
+    match peer {
+        Some(peer) => format!("{}.{}", peer.id, message_id),
+        None => message_id.to_string(),
+    }
+}
+
+fn modified_time(mtime: i32) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(mtime.max(0) as u64)
+}
+
+fn http_date(mtime: i32) -> String {
+    httpdate::fmt_http_date(modified_time(mtime))
+}
+
+/// Adds the validators and `Cache-Control`, which every response of the file (including 304) has.
+pub(crate) fn with_cache_headers(mut builder: Builder, etag: Option<&str>, mtime: Option<i32>) -> Builder {
+    if let Some(etag) = etag {
+        builder = builder.header("ETag", etag);
+    }
+    if let Some(mtime) = mtime {
+        builder = builder.header("Last-Modified", http_date(mtime));
+    }
+    builder.header("Cache-Control", CACHE_CONTROL)
+}
+
+/// `If-None-Match` is used instead of `If-Modified-Since` if both are given. `mtime` is `None` if not known yet.
+pub(crate) fn is_not_modified(headers: &HeaderMap, etag: Option<&str>, mtime: Option<i32>) -> bool {
+    if let Some(value) = headers.get("If-None-Match").and_then(|x| x.to_str().ok()) {
+        let etag = match etag {
+            Some(v) => v,
+            None => return false,
+        };
+        // weak comparison, so W/ prefixes are ignored
+        return value.split(',').map(|x| x.trim()).any(|x| x == "*" || x.trim_start_matches("W/") == etag);
+    }
+    let since = headers.get("If-Modified-Since")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| httpdate::parse_http_date(x).ok());
+    match (since, mtime) {
+        (Some(since), Some(mtime)) => modified_time(mtime) <= since,
+        _ => false,
+    }
+}
+
+/// Whether `Range` should be used, which `If-Range` allows only if the file is still the one the client has.
+pub(crate) fn is_range_fresh(headers: &HeaderMap, etag: Option<&str>, mtime: i32) -> bool {
+    let value = match headers.get("If-Range").and_then(|x| x.to_str().ok()) {
+        Some(v) => v.trim(),
+        None => return true,
     };
+    if value.starts_with('"') || value.starts_with("W/") {
+        // strong comparison, so weak tags never match
+        return Some(value) == etag;
+    }
+    httpdate::parse_http_date(value).ok() == Some(modified_time(mtime))
+}
+
+pub(crate) fn not_modified(etag: Option<&str>, mtime: Option<i32>) -> Response {
+    with_cache_headers(Response::builder().status(304), etag, mtime)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::{CompositeFileRefV1, PeerKind};
+
+    use super::*;
+
+    fn single(message_id: i32, peer: Option<PeerRef>, copy_id: u64) -> FileRef {
+        FileRef {
+            v1: Some(FileRefV1 { message_id, document_id: 0x1234, peer, copy_id, ..Default::default() }),
+            ..Default::default()
+        }
+    }
 
-    let file_ref = message_to_file_ref(res);
+    #[test]
+    fn etag_of_single() {
+        assert_eq!(file_etag(&single(10, None, 0)).unwrap(), "\"1234-10\"");
+        // refs which share the message
+        assert_eq!(file_etag(&single(10, None, 0)), file_etag(&single(10, None, 5)));
+        // a copy of the document
+        assert_ne!(file_etag(&single(10, None, 0)), file_etag(&single(11, None, 0)));
+        // the same message id in another chat
+        let peer = PeerRef { kind: PeerKind::Channel as i32, id: 99, access_hash: 1 };
+        assert_eq!(file_etag(&single(10, Some(peer), 0)).unwrap(), "\"1234-99.10\"");
+    }
 
-    return file_ref.map(|x| x.to_ref_string());
+    #[test]
+    fn etag_of_composite() {
+        let composite = |manifest_message_id: i32| FileRef {
+            composite_v1: Some(CompositeFileRefV1 {
+                manifest_message_id,
+                segments: vec![FileRefV1 { document_id: 0xab, ..Default::default() }, FileRefV1 { document_id: 0xcd, ..Default::default() }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(file_etag(&composite(30)).unwrap(), "\"ab-2-30\"");
+        assert_ne!(file_etag(&composite(30)), file_etag(&composite(31)));
+        assert_eq!(file_etag(&FileRef::default()), None);
+    }
 }
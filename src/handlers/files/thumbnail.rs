@@ -0,0 +1,88 @@
+use axum::{body::Body, response::Response};
+use grammers_client::{Client, grammers_tl_types as tl};
+
+use crate::{proto::FileRef, shared::{get_message, message_document}};
+
+/// Upper bound of a thumbnail size, they are small enough to be fetched with a single request.
+const MAX_THUMBNAIL_BYTES: i32 = 1024 * 1024;
+
+pub async fn get_thumbnail(client: &Client, file_ref: String) -> Response {
+    let file_ref = FileRef::from_ref_string(file_ref);
+    let file_ref = match file_ref {
+        Some(v) => v,
+        None => {
+            return Response::builder().status(404).body(Body::from("thumbnail not found")).unwrap();
+        }
+    };
+    let file_ref = match file_ref.v1 {
+        Some(v) => v,
+        None => {
+            return Response::builder().status(404).body(Body::from("thumbnail not found")).unwrap();
+        }
+    };
+
+    // thumbnail sizes are only known from the message, and it also gives us the latest file_reference
+    let message = match get_message(client, file_ref.peer.as_ref(), file_ref.message_id).await {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            return Response::builder().status(404).body(Body::from("thumbnail not found")).unwrap();
+        }
+        Err(e) => {
+            println!("failed to get message {:?}", e);
+            return Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap();
+        }
+    };
+
+    let doc = match message_document(&message) {
+        Some(v) if v.id == file_ref.document_id => v,
+        _ => {
+            return Response::builder().status(404).body(Body::from("thumbnail not found")).unwrap();
+        }
+    };
+
+    // pick the largest one
+    let thumb_size = doc.thumbs.iter().flatten().filter_map(|x| match x {
+        tl::enums::PhotoSize::Size(size) => Some((size.r#type.clone(), size.w * size.h)),
+        tl::enums::PhotoSize::Progressive(size) => Some((size.r#type.clone(), size.w * size.h)),
+        _ => None,
+    }).max_by_key(|x| x.1).map(|x| x.0);
+
+    let thumb_size = match thumb_size {
+        Some(v) => v,
+        None => {
+            return Response::builder().status(404).body(Body::from("thumbnail not found")).unwrap();
+        }
+    };
+
+    let req = tl::functions::upload::GetFile {
+        cdn_supported: false,
+        limit: MAX_THUMBNAIL_BYTES,
+        location: tl::enums::InputFileLocation::InputDocumentFileLocation(tl::types::InputDocumentFileLocation {
+            id: doc.id, access_hash: doc.access_hash, file_reference: doc.file_reference.clone(), thumb_size,
+        }),
+        precise: false,
+        offset: 0,
+    };
+
+    let res = match client.invoke(&req).await {
+        Ok(v) => v,
+        Err(e) => {
+            println!("failed to get thumbnail {:?}", e);
+            return Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap();
+        }
+    };
+
+    let res = match res {
+        tl::enums::upload::File::File(file) => file,
+        tl::enums::upload::File::CdnRedirect(file_cdn_redirect) => {
+            println!("TODO: redirected to cdn {:?}", file_cdn_redirect);
+            return Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap();
+        },
+    };
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "image/jpeg")
+        .body(Body::from(res.bytes))
+    .unwrap()
+}
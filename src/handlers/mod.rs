@@ -1,2 +1,4 @@
 pub mod upload;
-pub mod files;
\ No newline at end of file
+pub mod files;
+pub mod debug;
+pub mod admin;
\ No newline at end of file
@@ -0,0 +1,31 @@
+use axum::{body::Body, response::Response};
+
+use crate::{proto::UploadToken, uploads::UploadRegistry};
+
+#[derive(serde::Deserialize)]
+pub struct AbortUploadQueryParams {
+    token: String,
+}
+
+/// Forgets the upload. Parts already sent are left to upstream, which discards them eventually.
+pub async fn abort_upload(uploads: &UploadRegistry, query: AbortUploadQueryParams) -> Response {
+    let token = UploadToken::from_api_string(query.token);
+
+    let token = match token {
+        None => {
+            return Response::builder().status(400).body(Body::from("invalid token")).unwrap();
+        }
+        Some(t) => t,
+    };
+
+    let token = match token.v1 {
+        None => {
+            return Response::builder().status(400).body(Body::from("invalid token")).unwrap();
+        }
+        Some(t) => t,
+    };
+
+    uploads.abort(token.file_id);
+
+    Response::builder().status(204).body(Body::empty()).unwrap()
+}
@@ -1,7 +1,24 @@
 use axum::{body::Body, response::Response};
-use grammers_client::{Client, grammers_tl_types as tl};
+use grammers_client::{InvocationError, grammers_tl_types as tl};
 
-use crate::{proto::UploadToken, shared::CHUNK_SIZE};
+use crate::{crypto, proto::{UploadToken, UploadTokenV1}, transfers::TransferPool, uploads::UploadRegistry};
+
+/// Why a part couldn't be saved.
+#[derive(Debug)]
+pub(crate) enum SavePartError {
+    Upstream(InvocationError),
+    /// the file key in the token doesn't work
+    Encryption,
+}
+
+impl std::fmt::Display for SavePartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SavePartError::Upstream(e) => e.fmt(f),
+            SavePartError::Encryption => f.write_str("failed to encrypt the part"),
+        }
+    }
+}
 
 #[derive(serde::Deserialize)]
 pub struct UploadChunkQueryParams {
@@ -9,7 +26,7 @@ pub struct UploadChunkQueryParams {
     offset: u64,
 }
 
-pub async fn upload_chunk(client: &Client, query: UploadChunkQueryParams, body: Vec<u8>) -> Response {
+pub async fn upload_chunk(transfers: &TransferPool, uploads: &UploadRegistry, query: UploadChunkQueryParams, body: Vec<u8>) -> Response {
     let token = UploadToken::from_api_string(query.token);
 
     let token = match token {
@@ -26,41 +43,96 @@ pub async fn upload_chunk(client: &Client, query: UploadChunkQueryParams, body:
         Some(t) => t,
     };
 
-    if query.offset % (CHUNK_SIZE as u64) > 0 {
-        return Response::builder().status(400).body(Body::from(format!("offset should be divided by {}", CHUNK_SIZE))).unwrap();
+    if uploads.is_aborted(token.file_id) {
+        return Response::builder().status(410).body(Body::from("upload is aborted")).unwrap();
+    }
+
+    let chunk_size = token.chunk_size() as u64;
+    if query.offset % chunk_size > 0 {
+        return Response::builder().status(400).body(Body::from(format!("offset should be divided by {}", chunk_size))).unwrap();
+    }
+
+    if query.offset >= token.file_size as u64 {
+        return Response::builder().status(416).body(Body::from(format!("offset {} is out of the file (file_size is {})", query.offset, token.file_size))).unwrap();
     }
 
-    let current_part = (query.offset / (CHUNK_SIZE as u64)) as i32;
+    let current_part = (query.offset / chunk_size) as i32;
     println!("{}, {}", current_part, query.offset);
 
-    let res = if token.should_use_big_upload() {
-        // big
-        let req = tl::functions::upload::SaveBigFilePart {
-            bytes: body,
-            file_id: token.file_id,
-            file_part: current_part,
-            file_total_parts: token.total_parts(),
-        };
-        client.invoke(&req).await
-    } else {
-        // small
-        let req = tl::functions::upload::SaveFilePart {
-            bytes: body,
-            file_id: token.file_id,
-            file_part: current_part,
-        };
-        client.invoke(&req).await
+    let expected_size = match token.expected_part_size(current_part) {
+        Some(v) => v,
+        None => {
+            return Response::builder().status(416).body(Body::from(format!("part {} is out of the file (total_parts is {})", current_part, token.total_parts()))).unwrap();
+        }
     };
+    if body.len() > expected_size {
+        return Response::builder().status(413).body(Body::from(format!("chunk at offset {} should be {} bytes, but got {} bytes", query.offset, expected_size, body.len()))).unwrap();
+    }
+    if body.len() < expected_size {
+        return Response::builder().status(400).body(Body::from(format!("chunk at offset {} should be {} bytes, but got {} bytes", query.offset, expected_size, body.len()))).unwrap();
+    }
+
+    let (segment, _) = token.locate_part(current_part);
+    let res = save_part(transfers, uploads, &token, segment.should_use_big_upload(), segment.total_parts(), current_part, body).await;
 
     match res {
-        Ok(v) => {
-            println!("{}", v);
+        Ok(()) => {},
+        Err(SavePartError::Encryption) => {
+            println!("failed to encrypt part {} of {}", current_part, token.file_id);
+            return Response::builder().status(500).body(Body::from("failed to encrypt the chunk")).unwrap();
         },
-        Err(e) => {
+        Err(SavePartError::Upstream(e)) => {
             println!("failed to call upstream api {:?}", e);
             return Response::builder().status(400).body(Body::from("failed to call upstream api")).unwrap();
-        }
+        },
     }
 
     Response::builder().status(204).body(Body::empty()).unwrap()
-}
\ No newline at end of file
+}
+
+/// Sends a part to upstream and records it to the upload session.
+///
+/// `part` is the index in the whole file, while `big` and `total_parts` are of the segment which has it.
+/// `total_parts` is only used for big uploads, and can be -1 while the file size is unknown.
+/// `bytes` is the plaintext, which is encrypted here if the upload has a file key.
+/// Subscribers of the upload events are told the result.
+pub(crate) async fn save_part(transfers: &TransferPool, uploads: &UploadRegistry, token: &UploadTokenV1, big: bool, total_parts: i32, part: i32, bytes: Vec<u8>) -> Result<(), SavePartError> {
+    uploads.start_part(token, part);
+    let res = send_part(transfers, token, big, total_parts, part, &bytes).await;
+    match &res {
+        Ok(()) => uploads.record_part(token, part, &bytes),
+        Err(e) => uploads.fail_part(token, part, e.to_string()),
+    }
+    res
+}
+
+async fn send_part(transfers: &TransferPool, token: &UploadTokenV1, big: bool, total_parts: i32, part: i32, bytes: &[u8]) -> Result<(), SavePartError> {
+    let (segment, segment_part) = token.locate_part(part);
+    let stored = match &token.encryption {
+        // the key is generated by us and the token is sealed, but it may be broken (e.g. a token from an older version)
+        Some(encryption) => crypto::encrypt_part(encryption, part as u64, bytes).ok_or(SavePartError::Encryption)?,
+        None => bytes.to_vec(),
+    };
+    let res = if big {
+        let req = tl::functions::upload::SaveBigFilePart {
+            bytes: stored,
+            file_id: segment.file_id,
+            file_part: segment_part,
+            file_total_parts: total_parts,
+        };
+        transfers.invoke(&req).await.map_err(SavePartError::Upstream)?
+    } else {
+        let req = tl::functions::upload::SaveFilePart {
+            bytes: stored,
+            file_id: segment.file_id,
+            file_part: segment_part,
+        };
+        transfers.invoke(&req).await.map_err(SavePartError::Upstream)?
+    };
+
+    if !res {
+        println!("upstream didn't accept part {} of {}", segment_part, segment.file_id);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use std::convert::Infallible;
+
+use axum::{body::Body, response::{sse::{Event, KeepAlive, Sse}, IntoResponse, Response}};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{finalized::FinalizedUploads, proto::{FileRef, UploadToken}, uploads::{UploadEvent, UploadEventKind, UploadRegistry}};
+
+#[derive(serde::Deserialize)]
+pub struct UploadEventsQueryParams {
+    token: String,
+}
+
+pub async fn upload_events(uploads: &UploadRegistry, finalized: &FinalizedUploads, query: UploadEventsQueryParams) -> Response {
+    let token = UploadToken::from_api_string(query.token);
+
+    let token = match token {
+        None => {
+            return Response::builder().status(400).body(Body::from("invalid token")).unwrap();
+        }
+        Some(t) => t,
+    };
+
+    let token = match token.v1 {
+        None => {
+            return Response::builder().status(400).body(Body::from("invalid token")).unwrap();
+        }
+        Some(t) => t,
+    };
+
+    if uploads.is_aborted(token.file_id) {
+        return Response::builder().status(410).body(Body::from("upload is aborted")).unwrap();
+    }
+
+    // subscribe before looking up the result, so that the finalized event can't be missed between them.
+    // subscribers which come late only get the result
+    let receiver = uploads.subscribe(&token);
+    let receiver = match (finalized.get(token.file_id), receiver) {
+        (Some(r#ref), _) => {
+            let file_size = FileRef::from_ref_string(r#ref.clone()).map(|x| x.content_size()).unwrap_or(0);
+            let (sender, receiver) = broadcast::channel(1);
+            let _ = sender.send(UploadEvent {
+                kind: UploadEventKind::Finalized { r#ref },
+                bytes_done: file_size,
+                bytes_total: Some(file_size),
+            });
+            receiver
+        },
+        (None, Some(receiver)) => receiver,
+        (None, None) => {
+            return Response::builder().status(404).body(Body::from("upload not found")).unwrap();
+        }
+    };
+
+    // the stream ends after the finalized event, or when the upload is aborted or forgotten
+    let stream = futures_util::stream::unfold(Some(receiver), |receiver| async move {
+        let mut receiver = receiver?;
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let last = matches!(event.kind, UploadEventKind::Finalized { .. });
+                    let sse = Event::default().event(event.name()).json_data(&event).unwrap();
+                    return Some((Ok::<_, Infallible>(sse), if last { None } else { Some(receiver) }));
+                },
+                Err(RecvError::Lagged(skipped)) => {
+                    println!("upload event subscriber skipped {} events", skipped);
+                },
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default()).into_response()
+}
@@ -1,7 +1,14 @@
 use axum::{body::Body, response::Response};
-use grammers_client::{Client, grammers_tl_types as tl};
+use grammers_client::{Client, InvocationError, grammers_tl_types as tl};
 
-use crate::{proto::UploadToken, shared::message_to_file_ref};
+use prost::Message as _;
+
+use crate::{dedup::DedupIndex, finalized::FinalizedUploads, handlers::files::{copy::{copy_stored, CaptionChanges}, get_stored_file}, proto::{CompositeFileRefV1, FileEncryption, FileRef, PeerRef, UploadToken, UploadTokenV1}, shared::{find_sent_message, from_hex, is_valid_content_type, message_to_file_ref, to_hex, FileCaption, Manifest, SentDocument, BIG_UPLOAD_THRESHOLD, MAX_CAPTION_LENGTH, MAX_MESSAGE_LENGTH}, thumbnail, uploads::UploadRegistry};
+
+use super::start::new_file_id;
+
+/// Name of the documents which encrypted files are stored as.
+const ENCRYPTED_FILE_NAME: &str = "encrypted.bin";
 
 #[derive(serde::Deserialize)]
 pub struct UploadFinalizeQueryParams {
@@ -10,16 +17,36 @@ pub struct UploadFinalizeQueryParams {
 
 #[derive(serde::Deserialize)]
 pub struct UploadFinalizeBody {
-    md5: String,
-    name: String,
+    #[serde(default)]
+    pub md5: String,
+    #[serde(default)]
+    pub sha256: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    /// stored in the message caption, so it should be small
+    #[serde(default)]
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    /// send as a native media instead of a generic document
+    #[serde(default)]
+    pub media: Option<UploadMediaType>,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadMediaType {
+    /// re-encoded by upstream, so the stored file differs from the uploaded one
+    Photo,
+    Video,
+    Audio,
 }
 
 #[derive(serde::Serialize)]
 pub struct UploadFinalizeResponse {
-    r#ref: String,
+    pub r#ref: String,
 }
 
-pub async fn upload_finalize(query: UploadFinalizeQueryParams, body: UploadFinalizeBody, client: &Client) -> Response {
+pub async fn upload_finalize(query: UploadFinalizeQueryParams, body: UploadFinalizeBody, client: &Client, uploads: &UploadRegistry, dedup: &DedupIndex, finalized: &FinalizedUploads) -> Response {
     let token = UploadToken::from_api_string(query.token);
 
     let token = match token {
@@ -36,46 +63,502 @@ pub async fn upload_finalize(query: UploadFinalizeQueryParams, body: UploadFinal
         Some(t) => t,
     };
 
-    let file: tl::enums::InputFile = match token.should_use_big_upload() {
+    if uploads.is_aborted(token.file_id) {
+        return Response::builder().status(410).body(Body::from("upload is aborted")).unwrap();
+    }
+
+    // a retry after the response is lost
+    let r#ref = match finalized.get(token.file_id) {
+        Some(v) => v,
+        None => {
+            match finalize_upload(client, uploads, dedup, finalized, &token, body).await {
+                Ok(v) => v.to_ref_string(),
+                Err(res) => return res,
+            }
+        }
+    };
+
+    let res = UploadFinalizeResponse {
+        r#ref,
+    };
+    
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(&res).unwrap()))
+    .unwrap()
+}
+
+/// Finalizes the upload, remembers the ref for retries, and tells subscribers of the upload events.
+pub(crate) async fn finalize_upload(client: &Client, uploads: &UploadRegistry, dedup: &DedupIndex, finalized: &FinalizedUploads, token: &UploadTokenV1, body: UploadFinalizeBody) -> Result<FileRef, Response> {
+    let file_ref = send_upload(client, uploads, dedup, token, body).await?;
+    let r#ref = file_ref.to_ref_string();
+    // remembered before the session is removed, so that subscribers find either of them
+    finalized.insert(token.file_id, r#ref.clone());
+    uploads.remove(token.file_id);
+    uploads.notify_finalized(token.file_id, r#ref, file_ref.content_size());
+    Ok(file_ref)
+}
+
+/// Sends the uploaded file as a message, and returns the ref of it.
+async fn send_upload(client: &Client, uploads: &UploadRegistry, dedup: &DedupIndex, token: &UploadTokenV1, body: UploadFinalizeBody) -> Result<FileRef, Response> {
+    let (checksums, detected_content_type, buffered, media_info) = uploads.with_session(token, |s| (
+        s.checksum.finish(token.content_parts()),
+        s.detect_content_type(),
+        s.buffered_file(token.content_parts()),
+        match body.media {
+            Some(UploadMediaType::Video | UploadMediaType::Audio) => s.probe_media(token.file_size),
+            _ => None,
+        },
+    ));
+
+    if body.content_type.as_deref().is_some_and(|x| !is_valid_content_type(x)) {
+        return Err(Response::builder().status(400).body(Body::from("content_type should be a valid header value")).unwrap());
+    }
+    if body.media == Some(UploadMediaType::Photo) && token.should_use_big_upload() {
+        return Err(Response::builder().status(400).body(Body::from(format!("photos should be smaller than {} bytes", BIG_UPLOAD_THRESHOLD))).unwrap());
+    }
+    if body.media.is_some() && token.is_composite() {
+        return Err(Response::builder().status(400).body(Body::from("media can't be used for files larger than the per-file limit")).unwrap());
+    }
+    if body.media.is_some() && token.encryption.is_some() {
+        return Err(Response::builder().status(400).body(Body::from("media can't be used for encrypted files")).unwrap());
+    }
+    if body.media.is_some() && token.compression.is_some() {
+        return Err(Response::builder().status(400).body(Body::from("media can't be used for compressed files")).unwrap());
+    }
+
+    let md5 = match &checksums {
+        Some(checksums) => {
+            if !body.md5.is_empty() && !body.md5.eq_ignore_ascii_case(&checksums.md5) {
+                return Err(Response::builder().status(400).body(Body::from(format!("md5 mismatch (computed {})", checksums.md5))).unwrap());
+            }
+            if let Some(sha256) = &body.sha256 {
+                if !sha256.eq_ignore_ascii_case(&checksums.sha256) {
+                    return Err(Response::builder().status(400).body(Body::from(format!("sha256 mismatch (computed {})", checksums.sha256))).unwrap());
+                }
+            }
+            checksums.md5.clone()
+        },
+        // the client asked for verification, which can't be done (e.g. restarted while uploading)
+        None if !body.md5.is_empty() || body.sha256.is_some() => {
+            println!("checksums are not available for upload {}", token.file_id);
+            return Err(Response::builder().status(409).body(Body::from("checksum unavailable, omit md5 and sha256 to finalize without verification")).unwrap());
+        },
+        None => "".to_string(),
+    };
+
+    // identical files are stored only once, except native media since they are stored differently.
+    // encrypted files have their own key, and their hash shouldn't be discoverable either
+    let dedup_key = match (body.media, &token.encryption) {
+        (None, None) => checksums.as_ref().map(|x| x.sha256.clone()),
+        _ => None,
+    };
+    let mime_type = match body.content_type {
+        Some(v) if !v.is_empty() => v,
+        _ => detected_content_type.unwrap_or("application/octet-stream").to_string(),
+    };
+
+    if let Some(sha256) = &dedup_key {
+        if let Some(file_ref) = reuse_stored(client, dedup, sha256, token.file_id, &body.name, &mime_type, &body.metadata).await? {
+            return Ok(file_ref);
+        }
+    }
+
+    if token.is_composite() {
+        let file_ref = finalize_composite(client, token, body.name, mime_type, checksums.map(|x| x.sha256), body.metadata).await?;
+        return Ok(match &dedup_key {
+            Some(sha256) => dedup.insert(sha256, file_ref),
+            None => file_ref,
+        });
+    }
+
+    if let Some(encryption) = &token.encryption {
+        return finalize_encrypted(client, token, encryption, body.name, mime_type, checksums.map(|x| x.sha256), body.metadata).await;
+    }
+
+    let is_photo = body.media == Some(UploadMediaType::Photo);
+    // compressed files are stored as `.zst` so that they make sense when downloaded as is
+    let compressed = token.compression.is_some();
+    let caption = FileCaption {
+        // photos are re-encoded, so neither the checksum nor the name survives
+        sha256: if is_photo { None } else { checksums.map(|x| x.sha256) },
+        name: if is_photo || compressed { Some(body.name.clone()) } else { None },
+        mime_type: if compressed { Some(mime_type.clone()) } else { None },
+        metadata: body.metadata,
+        compression: token.compression.as_ref().map(|x| to_hex(&x.encode_to_vec())),
+        ..Default::default()
+    };
+    let caption = match caption_with_random_id(caption, token.file_id) {
+        Some(v) => v,
+        None => {
+            return Err(Response::builder().status(413).body(Body::from(format!("metadata is too large (should fit in {} characters with other data)", MAX_CAPTION_LENGTH))).unwrap());
+        }
+    };
+    let (name, mime_type, md5) = match compressed {
+        // md5 is of the content, so it can't be used to verify the stored parts
+        true => (format!("{}.zst", body.name), "application/zstd".to_string(), "".to_string()),
+        false => (body.name, mime_type, md5),
+    };
+
+    let mut attributes = vec![
+        tl::enums::DocumentAttribute::Filename(tl::types::DocumentAttributeFilename {
+            file_name: name.clone(),
+        }),
+    ];
+    match body.media {
+        Some(UploadMediaType::Video) => {
+            let info = media_info.unwrap_or_default();
+            attributes.push(tl::enums::DocumentAttribute::Video(tl::types::DocumentAttributeVideo {
+                round_message: false,
+                supports_streaming: info.supports_streaming,
+                nosound: false,
+                duration: info.duration.unwrap_or(0.0),
+                w: info.width.unwrap_or(0),
+                h: info.height.unwrap_or(0),
+                preload_prefix_size: None,
+            }));
+        },
+        Some(UploadMediaType::Audio) => {
+            let info = media_info.unwrap_or_default();
+            attributes.push(tl::enums::DocumentAttribute::Audio(tl::types::DocumentAttributeAudio {
+                voice: false,
+                duration: info.duration.unwrap_or(0.0).round() as i32,
+                title: info.title,
+                performer: info.performer,
+                waveform: None,
+            }));
+        },
+        Some(UploadMediaType::Photo) | None => {},
+    }
+
+    // photos are re-encoded, so they can only be found by `random_id`
+    let sent = match is_photo {
+        true => None,
+        false => Some(SentDocument { name: name.clone(), size: token.stored_size() }),
+    };
+    let file = input_file(token, name, md5);
+
+    let thumb = match buffered {
+        Some(data) if !is_photo => upload_thumbnail(client, data).await,
+        _ => None,
+    };
+
+    let media = match is_photo {
+        true => tl::enums::InputMedia::UploadedPhoto(tl::types::InputMediaUploadedPhoto {
+            spoiler: false,
+            file,
+            stickers: None,
+            ttl_seconds: None,
+        }),
+        false => tl::enums::InputMedia::UploadedDocument(tl::types::InputMediaUploadedDocument {
+            nosound_video: false,
+            force_file: body.media.is_none(),
+            spoiler: false,
+            file,
+            thumb,
+            mime_type,
+            attributes,
+            stickers: None,
+            ttl_seconds: None,
+        }),
+    };
+
+    let file_ref = send_media(client, None, media, caption, token.file_id, sent.as_ref()).await?;
+
+    Ok(match &dedup_key {
+        Some(sha256) => dedup.insert(sha256, file_ref),
+        None => file_ref,
+    })
+}
+
+/// Adds a copy of the stored file which has the same content, instead of sending the upload.
+/// If the caption would be different, the stored file is sent again with the new one, as the copy API does.
+///
+/// Returns `None` if no file has the content or it can't be used (e.g. deleted meanwhile), so that the upload should be sent instead.
+async fn reuse_stored(client: &Client, dedup: &DedupIndex, sha256: &str, random_id: i64, name: &str, mime_type: &str, metadata: &Option<serde_json::Map<String, serde_json::Value>>) -> Result<Option<FileRef>, Response> {
+    let existing = match dedup.find(client, sha256).await {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let stored = match get_stored_file(client, &existing).await {
+        Ok(v) => v,
+        Err(res) => {
+            println!("failed to get deduplicated file {} ({}), sending the upload", sha256, res.status());
+            return Ok(None);
+        }
+    };
+
+    let changes = CaptionChanges {
+        name: Some(name.to_string()).filter(|x| stored.name.as_ref() != Some(x)),
+        mime_type: Some(mime_type.to_string()).filter(|x| *x != stored.mime_type),
+        metadata: Some(metadata.clone()).filter(|x| *x != stored.metadata),
+    };
+    if changes.name.is_none() && changes.mime_type.is_none() && changes.metadata.is_none() {
+        return Ok(dedup.acquire(sha256, stored.file_ref));
+    }
+
+    // the copy has its own message, so it's not shared with other copies
+    copy_stored(client, &stored.file_ref, None, changes, random_id).await.map(Some)
+}
+
+fn input_file(token: &UploadTokenV1, name: String, md5: String) -> tl::enums::InputFile {
+    match token.should_use_big_upload() {
         true => {
             tl::enums::InputFile::Big(tl::types::InputFileBig {
                 id: token.file_id,
                 parts: token.total_parts(),
-                name: body.name,
+                name,
             })
         },
         false => {
             tl::enums::InputFile::File(tl::types::InputFile {
                 id: token.file_id,
-                md5_checksum: body.md5,
-                name: body.name,
+                md5_checksum: md5,
+                name,
                 parts: token.total_parts(),
             })
         }
+    }
+}
+
+/// Sends the encrypted file as a document whose name and type don't tell anything.
+/// The real ones and the file key are in the caption, which is sealed with the server key.
+async fn finalize_encrypted(client: &Client, token: &UploadTokenV1, encryption: &FileEncryption, name: String, mime_type: String, sha256: Option<String>, metadata: Option<serde_json::Map<String, serde_json::Value>>) -> Result<FileRef, Response> {
+    let caption = FileCaption {
+        sha256,
+        name: Some(name),
+        mime_type: Some(mime_type),
+        metadata,
+        key: Some(to_hex(&encryption.encode_to_vec())),
+        ..Default::default()
+    };
+    let caption = match caption_with_random_id(caption, token.file_id) {
+        Some(v) => v,
+        None => {
+            return Err(Response::builder().status(413).body(Body::from(format!("metadata is too large (should fit in {} characters with other data, after encryption)", MAX_CAPTION_LENGTH))).unwrap());
+        }
     };
 
-    let req = tl::functions::messages::SendMedia {
-        silent: true,
-        background: false,
-        clear_draft: false,
-        noforwards: true,
-        update_stickersets_order: false,
-        invert_media: false,
-        peer: tl::enums::InputPeer::PeerSelf,
-        reply_to: None,
-        media: tl::enums::InputMedia::UploadedDocument(tl::types::InputMediaUploadedDocument {
+    let media = tl::enums::InputMedia::UploadedDocument(tl::types::InputMediaUploadedDocument {
+        nosound_video: false,
+        force_file: true,
+        spoiler: false,
+        // md5 is of the plaintext, so it can't be used to verify the stored parts
+        file: input_file(token, ENCRYPTED_FILE_NAME.to_string(), "".to_string()),
+        thumb: None,
+        mime_type: "application/octet-stream".to_string(),
+        attributes: vec![
+            tl::enums::DocumentAttribute::Filename(tl::types::DocumentAttributeFilename {
+                file_name: ENCRYPTED_FILE_NAME.to_string(),
+            }),
+        ],
+        stickers: None,
+        ttl_seconds: None,
+    });
+
+    let sent = SentDocument { name: ENCRYPTED_FILE_NAME.to_string(), size: token.stored_size() };
+    send_media(client, None, media, caption, token.file_id, Some(&sent)).await
+}
+
+/// Sends each segment as a document, and then a text message with the manifest which ties them together.
+async fn finalize_composite(client: &Client, token: &UploadTokenV1, name: String, mime_type: String, sha256: Option<String>, metadata: Option<serde_json::Map<String, serde_json::Value>>) -> Result<FileRef, Response> {
+    let segments = token.segments();
+    // segments use file_id + index
+    let manifest_random_id = token.file_id.wrapping_add(segments.len() as i64);
+
+    let mut caption = FileCaption {
+        sha256,
+        random_id: Some(manifest_random_id),
+        metadata,
+        key: token.encryption.as_ref().map(|x| to_hex(&x.encode_to_vec())),
+        manifest: Some(Manifest {
+            name: name.clone(),
+            mime_type,
+            file_size: token.file_size,
+            segment_size: token.segment_size,
+            // placeholders to check the length before sending anything
+            segments: vec![i32::MAX; segments.len()],
+        }),
+        ..Default::default()
+    };
+    if manifest_text(&caption).encode_utf16().count() > MAX_MESSAGE_LENGTH {
+        return Err(Response::builder().status(413).body(Body::from(format!("metadata is too large (should fit in {} characters with other data)", MAX_MESSAGE_LENGTH))).unwrap());
+    }
+
+    let mut segment_refs = vec![];
+    for (i, segment) in segments.iter().enumerate() {
+        let segment_name = match token.encryption {
+            Some(_) => format!("{}.{:03}", ENCRYPTED_FILE_NAME, i),
+            None => format!("{}.{:03}", name, i),
+        };
+        let media = tl::enums::InputMedia::UploadedDocument(tl::types::InputMediaUploadedDocument {
             nosound_video: false,
             force_file: true,
             spoiler: false,
-            file,
+            file: input_file(segment, segment_name.clone(), "".to_string()),
             thumb: None,
             mime_type: "application/octet-stream".to_string(),
-            attributes: vec![],
+            attributes: vec![
+                tl::enums::DocumentAttribute::Filename(tl::types::DocumentAttributeFilename {
+                    file_name: segment_name,
+                }),
+            ],
             stickers: None,
             ttl_seconds: None,
+        });
+        let segment_caption = FileCaption {
+            segment: Some(i as u32),
+            random_id: Some(segment.file_id),
+            ..Default::default()
+        }.to_text();
+
+        let file_ref = send_media(client, None, media, segment_caption, segment.file_id, None).await?;
+        match file_ref.v1 {
+            Some(v) => segment_refs.push(v),
+            None => {
+                return Err(Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap());
+            }
+        }
+    }
+
+    if let Some(manifest) = &mut caption.manifest {
+        manifest.segments = segment_refs.iter().map(|x| x.message_id).collect();
+    }
+    let manifest_message_id = send_manifest(client, None, &caption, manifest_random_id).await?;
+
+    Ok(FileRef {
+        composite_v1: Some(CompositeFileRefV1 {
+            manifest_message_id,
+            file_size: token.file_size,
+            segment_size: token.segment_size,
+            segments: segment_refs,
+            sha256: caption.sha256.and_then(|x| from_hex(&x)).unwrap_or_default(),
+            encryption: token.encryption.clone(),
+            peer: None,
+            copy_id: 0,
+        }),
+        ..Default::default()
+    })
+}
+
+/// Sends the manifest as a text message, and returns the message id of it.
+pub(crate) async fn send_manifest(client: &Client, peer: Option<&PeerRef>, caption: &FileCaption, random_id: i64) -> Result<i32, Response> {
+    let req = tl::functions::messages::SendMessage {
+        no_webpage: true,
+        silent: true,
+        background: false,
+        clear_draft: false,
+        noforwards: true,
+        update_stickersets_order: false,
+        invert_media: false,
+        peer: PeerRef::input_peer(peer),
+        reply_to: None,
+        message: manifest_text(caption),
+        random_id,
+        reply_markup: None,
+        entities: None,
+        schedule_date: None,
+        send_as: None,
+        quick_reply_shortcut: None,
+        effect: None,
+    };
+
+    let manifest_message_id = match client.invoke(&req).await {
+        Ok(res) => sent_message_id(&res),
+        Err(InvocationError::Rpc(e)) if e.name == "RANDOM_ID_DUPLICATE" => Some(find_duplicate(client, peer, random_id, None).await?.id),
+        Err(e) => {
+            println!("failed to send manifest to upstream {:?}", e);
+            return Err(Response::builder().status(400).body(Body::from("failed to call upstream api")).unwrap());
+        }
+    };
+    match manifest_message_id {
+        Some(v) => Ok(v),
+        None => Err(Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap()),
+    }
+}
+
+/// Returns the caption text with `random_id`, or `None` if the caption is too long even without it.
+///
+/// The id is only to find the message when the response of sending it is lost, so it isn't counted against the metadata:
+/// it's left out if it doesn't fit, or if the caption would be empty otherwise. Such messages are found by their document.
+fn caption_with_random_id(mut caption: FileCaption, random_id: i64) -> Option<String> {
+    let text = manifest_text(&caption);
+    if text.encode_utf16().count() > MAX_CAPTION_LENGTH {
+        return None;
+    }
+    if text.is_empty() {
+        return Some(text);
+    }
+    caption.random_id = Some(random_id);
+    let with_random_id = manifest_text(&caption);
+    match with_random_id.encode_utf16().count() > MAX_CAPTION_LENGTH {
+        true => Some(text),
+        false => Some(with_random_id),
+    }
+}
+
+/// Manifests of encrypted files are sealed, since they have the file key.
+pub(crate) fn manifest_text(caption: &FileCaption) -> String {
+    match caption.key {
+        Some(_) => caption.seal().to_text(),
+        None => caption.to_text(),
+    }
+}
+
+/// Returns the message id which upstream returns for a sent text message.
+fn sent_message_id(res: &tl::enums::Updates) -> Option<i32> {
+    let id = match res {
+        tl::enums::Updates::UpdateShortSentMessage(m) => Some(m.id),
+        tl::enums::Updates::Updates(updates) => updates.updates.iter().find_map(|u| match u {
+            tl::enums::Update::NewMessage(m) => match &m.message {
+                tl::enums::Message::Message(message) => Some(message.id),
+                _ => None,
+            },
+            _ => None,
         }),
-        message: "".to_string(),
-        random_id: token.file_id,
+        _ => None,
+    };
+    if id.is_none() {
+        println!("upstream returns unexpected updates {:?}", res);
+    }
+    id
+}
+
+/// Returns the message which was sent with `random_id` before, when upstream says it's a duplicate.
+async fn find_duplicate(client: &Client, peer: Option<&PeerRef>, random_id: i64, document: Option<&SentDocument>) -> Result<tl::types::Message, Response> {
+    match find_sent_message(client, peer, random_id, document).await {
+        Ok(Some(v)) => {
+            println!("message {} was already sent with random_id {}", v.id, random_id);
+            Ok(v)
+        },
+        Ok(None) => {
+            println!("upstream says random_id {} is duplicate, but the message is not found", random_id);
+            Err(Response::builder().status(409).body(Body::from("the message was already sent, but it is not found")).unwrap())
+        },
+        Err(e) => {
+            println!("failed to find sent message {:?}", e);
+            Err(Response::builder().status(500).body(Body::from("failed to fetch from upstream")).unwrap())
+        },
+    }
+}
+
+/// Sends a media to the chat (`None` for Saved Messages), and returns the ref of it.
+///
+/// `document` finds the message if it's sent already, when the caption may not have `random_id`.
+pub(crate) async fn send_media(client: &Client, peer: Option<&PeerRef>, media: tl::enums::InputMedia, caption: String, random_id: i64, document: Option<&SentDocument>) -> Result<FileRef, Response> {
+    let req = tl::functions::messages::SendMedia {
+        silent: true,
+        background: false,
+        clear_draft: false,
+        noforwards: true,
+        update_stickersets_order: false,
+        invert_media: false,
+        peer: PeerRef::input_peer(peer),
+        reply_to: None,
+        media,
+        message: caption,
+        random_id,
         reply_markup: None,
         entities: None,
         schedule_date: None,
@@ -88,9 +571,20 @@ pub async fn upload_finalize(query: UploadFinalizeQueryParams, body: UploadFinal
 
     let res = match res {
         Ok(v) => v,
+        Err(InvocationError::Rpc(e)) if e.name == "RANDOM_ID_DUPLICATE" => {
+            // sent already, but the response was lost
+            let message = find_duplicate(client, peer, random_id, document).await?;
+            return match message_to_file_ref(&message) {
+                Some(mut v) => {
+                    v.set_peer(peer.cloned());
+                    Ok(v)
+                },
+                None => Err(Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap()),
+            };
+        }
         Err(e) => {
             println!("failed to send message to upstream {:?}", e);
-            return Response::builder().status(400).body(Body::from("failed to call upstream api")).unwrap();
+            return Err(Response::builder().status(400).body(Body::from("failed to call upstream api")).unwrap());
         }
     };
 
@@ -98,7 +592,7 @@ pub async fn upload_finalize(query: UploadFinalizeQueryParams, body: UploadFinal
         tl::enums::Updates::Updates(updates) => updates,
         _ => {
             println!("upstream returns unexpected updates {:?}", res);
-            return Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap();
+            return Err(Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap());
         }
     };
 
@@ -111,7 +605,7 @@ pub async fn upload_finalize(query: UploadFinalizeQueryParams, body: UploadFinal
         Some(v) => v,
         None => {
             println!("upstream doesn't return NewMessage in updates {:?}", res.updates);
-            return Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap();
+            return Err(Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap());
         }
     };
 
@@ -119,24 +613,91 @@ pub async fn upload_finalize(query: UploadFinalizeQueryParams, body: UploadFinal
         tl::enums::Message::Message(message) => message,
         _ => {
             println!("upstream doesn't return Message {:?}", res.message);
-            return Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap();
+            return Err(Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap());
         }
     };
 
-    let file_ref = match message_to_file_ref(res) {
+    let mut file_ref = match message_to_file_ref(res) {
         Some(v) => v,
         None => {
-            return Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap();
+            return Err(Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap());
         }
     };
+    file_ref.set_peer(peer.cloned());
 
-    let res = UploadFinalizeResponse {
-        r#ref: file_ref.to_ref_string(),
+    Ok(file_ref)
+}
+
+/// Generates a thumbnail from the whole file and uploads it, or returns `None` if it's not an image.
+///
+/// Failures are not fatal since the file itself is already uploaded.
+async fn upload_thumbnail(client: &Client, data: Vec<u8>) -> Option<tl::enums::InputFile> {
+    let thumbnail = match tokio::task::spawn_blocking(move || thumbnail::generate(&data)).await {
+        Ok(Some(v)) => v,
+        Ok(None) => return None,
+        Err(e) => {
+            println!("thumbnail task failed {:?}", e);
+            return None;
+        }
     };
-    
-    Response::builder()
-        .status(200)
-        .header("Content-Type", "application/json")
-        .body(Body::from(serde_json::to_vec(&res).unwrap()))
-    .unwrap()
-}
\ No newline at end of file
+
+    let file_id = new_file_id();
+    let req = tl::functions::upload::SaveFilePart {
+        file_id,
+        file_part: 0,
+        bytes: thumbnail,
+    };
+    match client.invoke(&req).await {
+        Ok(true) => {},
+        res => {
+            println!("failed to upload thumbnail {:?}", res);
+            return None;
+        }
+    }
+
+    Some(tl::enums::InputFile::File(tl::types::InputFile {
+        id: file_id,
+        parts: 1,
+        name: "thumb.jpg".to_string(),
+        md5_checksum: "".to_string(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caption_with_metadata(value_length: usize) -> FileCaption {
+        let mut metadata = serde_json::Map::new();
+        metadata.insert("a".to_string(), serde_json::Value::String("x".repeat(value_length)));
+        FileCaption {
+            sha256: Some("0".repeat(64)),
+            metadata: Some(metadata),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn random_id_in_caption() {
+        let text = caption_with_random_id(caption_with_metadata(1), 42).unwrap();
+        assert_eq!(FileCaption::parse(&text).and_then(|x| x.random_id), Some(42));
+    }
+
+    #[test]
+    fn empty_caption_stays_empty() {
+        assert_eq!(caption_with_random_id(FileCaption::default(), 42), Some("".to_string()));
+    }
+
+    #[test]
+    fn random_id_is_not_counted() {
+        let base_length = caption_with_metadata(0).to_text().len();
+        let full = caption_with_metadata(MAX_CAPTION_LENGTH - base_length);
+
+        // the metadata fills the caption, so the id is left out
+        let text = caption_with_random_id(full, 42).unwrap();
+        assert_eq!(text.len(), MAX_CAPTION_LENGTH);
+        assert_eq!(FileCaption::parse(&text).and_then(|x| x.random_id), None);
+
+        assert!(caption_with_random_id(caption_with_metadata(MAX_CAPTION_LENGTH - base_length + 1), 42).is_none());
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use axum::{body::Body, extract::Multipart, response::{IntoResponse, Response}};
+use grammers_client::Client;
+
+use crate::{dedup::DedupIndex, finalized::FinalizedUploads, transfers::TransferPool, uploads::UploadRegistry};
+
+use super::{limit::UploadLimitCache, pipeline::StreamingUpload, start::parse_compression};
+
+#[derive(serde::Deserialize)]
+pub struct UploadFormQueryParams {
+    /// generate thumbnails for images
+    #[serde(default)]
+    thumbnail: bool,
+    /// `zstd` to store the files compressed
+    #[serde(default)]
+    compression: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct FormUploadedFile {
+    field: String,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    r#ref: String,
+}
+
+#[derive(serde::Serialize)]
+struct FormUploadResponse {
+    files: Vec<FormUploadedFile>,
+}
+
+/// Uploads every file part of a `multipart/form-data` body. Parts without a filename are ignored.
+pub async fn upload_form(client: &Client, transfers: &Arc<TransferPool>, uploads: &Arc<UploadRegistry>, dedup: &Arc<DedupIndex>, finalized: &Arc<FinalizedUploads>, limits: &UploadLimitCache, query: UploadFormQueryParams, mut multipart: Multipart) -> Response {
+    let compression = match parse_compression(query.compression.as_deref(), false) {
+        Ok(v) => v,
+        Err(res) => return res,
+    };
+    let file_size_limit = match limits.file_size_limit(client).await {
+        Ok(v) => v as u64,
+        Err(res) => return res,
+    };
+    let mut files = vec![];
+
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(v)) => v,
+            Ok(None) => break,
+            Err(e) => {
+                println!("failed to read multipart body {:?}", e);
+                return e.into_response();
+            }
+        };
+
+        let name = match field.file_name() {
+            Some(v) if !v.is_empty() => v.to_string(),
+            _ => continue,
+        };
+        let field_name = field.name().unwrap_or("").to_string();
+        let content_type = field.content_type().map(|x| x.to_string());
+
+        let mut upload = StreamingUpload::new(client.clone(), transfers.clone(), uploads.clone(), dedup.clone(), finalized.clone(), None, query.thumbnail, None, compression.clone());
+        upload.set_size_limit(file_size_limit);
+        loop {
+            let data = match field.chunk().await {
+                Ok(Some(v)) => v,
+                Ok(None) => break,
+                Err(e) => {
+                    println!("failed to read multipart body {:?}", e);
+                    upload.abort();
+                    return e.into_response();
+                }
+            };
+            if let Err(res) = upload.write(&data).await {
+                upload.abort();
+                return res;
+            }
+        }
+
+        let file_ref = match upload.finish(name.clone(), content_type.clone()).await {
+            Ok(v) => v,
+            Err(res) => return res,
+        };
+
+        files.push(FormUploadedFile {
+            field: field_name,
+            name,
+            content_type,
+            r#ref: file_ref.to_ref_string(),
+        });
+    }
+
+    if files.is_empty() {
+        return Response::builder().status(400).body(Body::from("no files in the form")).unwrap();
+    }
+
+    let res = FormUploadResponse { files };
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(&res).unwrap()))
+        .unwrap()
+}
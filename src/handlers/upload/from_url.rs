@@ -0,0 +1,277 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use axum::{body::Body, response::Response};
+use futures_util::StreamExt;
+use grammers_client::Client;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+use crate::{config, dedup::DedupIndex, finalized::FinalizedUploads, shared::to_hex, transfers::TransferPool, uploads::UploadRegistry};
+
+use super::{limit::UploadLimitCache, pipeline::StreamingUpload, start::{parse_compression, parse_encryption}};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Gives up if the remote server sends nothing for this long.
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(serde::Deserialize)]
+pub struct UploadFromUrlBody {
+    url: String,
+    /// taken from the URL path if omitted
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    thumbnail: bool,
+    #[serde(default)]
+    encryption: Option<String>,
+    #[serde(default)]
+    compression: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct UploadFromUrlResponse {
+    job_id: String,
+}
+
+#[derive(serde::Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum FetchJobState {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(serde::Serialize, Clone)]
+struct FetchJob {
+    state: FetchJobState,
+    url: String,
+    received_size: u64,
+    /// from `Content-Length`, if the remote server sent it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    r#ref: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip)]
+    updated_at: Instant,
+}
+
+/// Uploads which teleton fetches from remote URLs, keyed by job ID.
+///
+/// Jobs are in memory only, so they are lost (and running ones are cancelled) on restart.
+pub struct FetchJobRegistry {
+    http: reqwest::Client,
+    jobs: Mutex<HashMap<String, FetchJob>>,
+}
+
+impl FetchJobRegistry {
+    pub fn new() -> FetchJobRegistry {
+        let http = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .read_timeout(READ_TIMEOUT)
+            // every hop should be allowed, not only the first one
+            .redirect(reqwest::redirect::Policy::custom(|attempt| {
+                if attempt.previous().len() > config::get().fetch_max_redirects {
+                    attempt.error("too many redirects")
+                } else if !is_allowed_url(attempt.url()) {
+                    attempt.error("redirected to a host which is not allowed")
+                } else {
+                    attempt.follow()
+                }
+            }))
+            .build()
+            .expect("Failed to build HTTP client");
+
+        FetchJobRegistry {
+            http,
+            jobs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn update(&self, job_id: &str, f: impl FnOnce(&mut FetchJob)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(job_id) {
+            f(job);
+            job.updated_at = Instant::now();
+        }
+    }
+
+    fn fail(&self, job_id: &str, error: String) {
+        println!("failed to upload from url (job {}): {}", job_id, error);
+        self.update(job_id, |job| {
+            job.state = FetchJobState::Failed;
+            job.error = Some(error);
+        });
+    }
+
+    /// Forgets jobs which have finished longer than `ttl` ago, and returns how many jobs were forgotten.
+    pub fn expire_idle(&self, ttl: Duration) -> usize {
+        let mut jobs = self.jobs.lock().unwrap();
+        let before = jobs.len();
+        jobs.retain(|_, job| job.state == FetchJobState::Running || job.updated_at.elapsed() < ttl);
+        before - jobs.len()
+    }
+}
+
+fn is_allowed_url(url: &reqwest::Url) -> bool {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return false;
+    }
+    let host = match url.host_str() {
+        Some(v) => v.to_ascii_lowercase(),
+        None => return false,
+    };
+    config::get().fetch_allowed_hosts.iter().any(|allowed| match allowed.strip_prefix('.') {
+        Some(domain) => host == domain || host.ends_with(allowed.as_str()),
+        None => host == *allowed,
+    })
+}
+
+/// Returns the text of an error response, which is what the job reports instead.
+async fn response_error(res: Response) -> String {
+    let status = res.status();
+    match axum::body::to_bytes(res.into_body(), 64 * 1024).await {
+        Ok(body) => format!("{} ({})", String::from_utf8_lossy(&body), status.as_u16()),
+        Err(_) => format!("failed with status {}", status.as_u16()),
+    }
+}
+
+pub async fn upload_from_url(client: &Client, transfers: &Arc<TransferPool>, uploads: &Arc<UploadRegistry>, dedup: &Arc<DedupIndex>, finalized: &Arc<FinalizedUploads>, limits: &UploadLimitCache, jobs: &Arc<FetchJobRegistry>, body: UploadFromUrlBody) -> Response {
+    if config::get().fetch_allowed_hosts.is_empty() {
+        return Response::builder().status(403).body(Body::from("uploading from url is disabled since TELETON_FETCH_ALLOWED_HOSTS is not configured")).unwrap();
+    }
+
+    let url = match reqwest::Url::parse(&body.url) {
+        Ok(v) => v,
+        Err(_) => {
+            return Response::builder().status(400).body(Body::from("invalid url")).unwrap();
+        }
+    };
+    if !is_allowed_url(&url) {
+        return Response::builder().status(403).body(Body::from("the host is not allowed")).unwrap();
+    }
+
+    let encryption = match parse_encryption(body.encryption.as_deref(), body.thumbnail) {
+        Ok(v) => v,
+        Err(res) => return res,
+    };
+    let compression = match parse_compression(body.compression.as_deref(), encryption.is_some()) {
+        Ok(v) => v,
+        Err(res) => return res,
+    };
+
+    // streaming uploads are not split into segments, so they should fit in the upload limit
+    let max_size = match limits.file_size_limit(client).await {
+        Ok(v) => v as u64,
+        Err(res) => return res,
+    };
+    let max_size = match config::get().fetch_max_size {
+        Some(v) => u64::min(v, max_size),
+        None => max_size,
+    };
+
+    let name = match body.name {
+        Some(v) if !v.is_empty() => v,
+        _ => url.path_segments().and_then(|x| x.last()).filter(|x| !x.is_empty()).unwrap_or("file").to_string(),
+    };
+
+    let mut job_id = [0u8; 16];
+    StdRng::from_entropy().fill_bytes(&mut job_id);
+    let job_id = to_hex(&job_id);
+    jobs.jobs.lock().unwrap().insert(job_id.clone(), FetchJob {
+        state: FetchJobState::Running,
+        url: url.to_string(),
+        received_size: 0,
+        file_size: None,
+        r#ref: None,
+        error: None,
+        updated_at: Instant::now(),
+    });
+
+    let upload = StreamingUpload::new(client.clone(), transfers.clone(), uploads.clone(), dedup.clone(), finalized.clone(), None, body.thumbnail, encryption, compression);
+    {
+        let jobs = jobs.clone();
+        let job_id = job_id.clone();
+        tokio::spawn(async move {
+            run_job(&jobs, &job_id, upload, url, name, max_size).await;
+        });
+    }
+
+    Response::builder()
+        .status(202)
+        .header("Content-Type", "application/json")
+        .header("Location", format!("/v1/upload/from-url/{}", job_id))
+        .body(Body::from(serde_json::to_vec(&UploadFromUrlResponse { job_id }).unwrap()))
+        .unwrap()
+}
+
+async fn run_job(jobs: &FetchJobRegistry, job_id: &str, mut upload: StreamingUpload, url: reqwest::Url, name: String, max_size: u64) {
+    let res = match jobs.http.get(url).send().await {
+        Ok(v) => v,
+        Err(e) => {
+            upload.abort();
+            return jobs.fail(job_id, format!("failed to fetch: {}", e));
+        }
+    };
+    if !res.status().is_success() {
+        upload.abort();
+        return jobs.fail(job_id, format!("remote server responded with status {}", res.status().as_u16()));
+    }
+
+    let file_size = res.content_length();
+    if let Some(file_size) = file_size {
+        if file_size > max_size {
+            upload.abort();
+            return jobs.fail(job_id, format!("file is too large ({} bytes, should be at most {} bytes)", file_size, max_size));
+        }
+    }
+    jobs.update(job_id, |job| job.file_size = file_size);
+
+    let content_type = res.headers().get("Content-Type").and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+
+    let mut received_size = 0;
+    let mut body = res.bytes_stream();
+    while let Some(data) = body.next().await {
+        let data = match data {
+            Ok(v) => v,
+            Err(e) => {
+                upload.abort();
+                return jobs.fail(job_id, format!("failed to read response body: {}", e));
+            }
+        };
+        received_size += data.len() as u64;
+        // Content-Length can be absent or wrong
+        if received_size > max_size {
+            upload.abort();
+            return jobs.fail(job_id, format!("file is too large (should be at most {} bytes)", max_size));
+        }
+        if let Err(res) = upload.write(&data).await {
+            upload.abort();
+            return jobs.fail(job_id, response_error(res).await);
+        }
+        jobs.update(job_id, |job| job.received_size = received_size);
+    }
+
+    match upload.finish(name, content_type).await {
+        Ok(file_ref) => jobs.update(job_id, |job| {
+            job.state = FetchJobState::Succeeded;
+            job.r#ref = Some(file_ref.to_ref_string());
+        }),
+        Err(res) => jobs.fail(job_id, response_error(res).await),
+    }
+}
+
+pub async fn get_fetch_job(jobs: &FetchJobRegistry, job_id: String) -> Response {
+    let job = match jobs.jobs.lock().unwrap().get(&job_id) {
+        Some(v) => v.clone(),
+        None => {
+            return Response::builder().status(404).body(Body::from("job not found")).unwrap();
+        }
+    };
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(&job).unwrap()))
+        .unwrap()
+}
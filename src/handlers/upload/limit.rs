@@ -1,15 +1,86 @@
+use std::time::{Duration, Instant};
+
 use axum::{body::Body, response::Response};
 use grammers_client::{grammers_tl_types as tl, Client};
 
-use crate::shared::CHUNK_SIZE;
+use crate::config;
 
+/// How long the cached app config and premium status are used without asking upstream.
+const CACHE_TTL: Duration = Duration::from_secs(10 * 60);
 
 #[derive(serde::Serialize)]
 struct UploadLimitResponse {
     file_size_limit: usize,
 }
 
-pub async fn get_upload_limit(client: &Client) -> Response {
+struct CachedAppConfig {
+    hash: i32,
+    config: tl::types::JsonObject,
+    premium: bool,
+    fetched_at: Instant,
+}
+
+/// Caches `help.getAppConfig` and premium status of the current user, to know the upload limit cheaply.
+#[derive(Default)]
+pub struct UploadLimitCache {
+    cached: tokio::sync::Mutex<Option<CachedAppConfig>>,
+}
+
+impl UploadLimitCache {
+    pub fn new() -> UploadLimitCache {
+        UploadLimitCache::default()
+    }
+
+    /// Returns the max file size which can be uploaded by the current user, with parts of the configured size.
+    pub async fn file_size_limit(&self, client: &Client) -> Result<usize, Response> {
+        Ok(self.max_part_count(client).await? * config::get().upload_chunk_size)
+    }
+
+    /// Returns how many parts a file uploaded by the current user can have.
+    pub async fn max_part_count(&self, client: &Client) -> Result<usize, Response> {
+        let mut cached = self.cached.lock().await;
+
+        let needs_refresh = match &*cached {
+            None => true,
+            Some(c) => c.fetched_at.elapsed() > CACHE_TTL,
+        };
+        if needs_refresh {
+            let premium = fetch_premium(client).await?;
+            let hash = cached.as_ref().map(|c| c.hash).unwrap_or(0);
+            let config = match fetch_app_config(client, hash).await? {
+                Some(v) => v,
+                None => match cached.take() {
+                    Some(c) => (c.hash, c.config),
+                    None => {
+                        println!("Upstream says config is not modified, but we don't have it");
+                        return Err(Response::builder().status(500).body(Body::from("Failed to call upstream config API")).unwrap());
+                    },
+                },
+            };
+            *cached = Some(CachedAppConfig {
+                hash: config.0,
+                config: config.1,
+                premium,
+                fetched_at: Instant::now(),
+            });
+        }
+        let cached = cached.as_ref().unwrap();
+
+        let max_chunk_count_key = if cached.premium { "upload_max_fileparts_premium" } else { "upload_max_fileparts_default" };
+
+        let max_chunk_count = match get_json_number(&cached.config, max_chunk_count_key) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Failed to get max chunk count from config: {}", e);
+                return Err(Response::builder().status(500).body(Body::from("Failed to get max chunk count from upstream API")).unwrap());
+            }
+        };
+
+        Ok(max_chunk_count as usize)
+    }
+}
+
+async fn fetch_premium(client: &Client) -> Result<bool, Response> {
     let user = tl::functions::users::GetUsers {
         id: vec![tl::enums::InputUser::UserSelf]
     };
@@ -18,65 +89,69 @@ pub async fn get_upload_limit(client: &Client) -> Response {
         Ok(v) => v,
         Err(e) => {
             println!("Failed to get current my status {:?}", e);
-            return Response::builder().status(500).body(Body::from("Failed to call upstream user API")).unwrap();
+            return Err(Response::builder().status(500).body(Body::from("Failed to call upstream user API")).unwrap());
         }
     };
 
-    let user = &user[0];
-
-    let user = match user {
-        tl::enums::User::Empty(user_empty) => todo!(),
-        tl::enums::User::User(user) => user,
-    };
+    match user.first() {
+        Some(tl::enums::User::User(user)) => Ok(user.premium),
+        user => {
+            println!("Upstream doesn't return current user {:?}", user);
+            Err(Response::builder().status(500).body(Body::from("Failed to call upstream user API")).unwrap())
+        },
+    }
+}
 
+/// Returns `None` if the config is not modified since `hash`.
+async fn fetch_app_config(client: &Client, hash: i32) -> Result<Option<(i32, tl::types::JsonObject)>, Response> {
     let config = tl::functions::help::GetAppConfig {
-        hash: 0,
+        hash,
     };
 
     let config = match client.invoke(&config).await {
         Ok(v) => v,
         Err(e) => {
             println!("Failed to get config {:?}", e);
-            return Response::builder().status(500).body(Body::from("Failed to call upstream config API")).unwrap();
+            return Err(Response::builder().status(500).body(Body::from("Failed to call upstream config API")).unwrap());
         }
     };
 
     let config = match config {
-        tl::enums::help::AppConfig::NotModified => todo!(),
+        tl::enums::help::AppConfig::NotModified => return Ok(None),
         tl::enums::help::AppConfig::Config(app_config) => app_config,
     };
 
-    let config: tl::types::JsonObject = match config.config {
-        tl::enums::Jsonvalue::JsonNull => todo!(),
-        tl::enums::Jsonvalue::JsonBool(json_bool) => todo!(),
-        tl::enums::Jsonvalue::JsonNumber(json_number) => todo!(),
-        tl::enums::Jsonvalue::JsonString(json_string) => todo!(),
-        tl::enums::Jsonvalue::JsonArray(json_array) => todo!(),
-        tl::enums::Jsonvalue::JsonObject(json_object) => json_object,
-    };
-
-    let max_chunk_count_key = if user.premium { "upload_max_fileparts_premium" } else { "upload_max_fileparts_default" };
+    match config.config {
+        tl::enums::Jsonvalue::JsonObject(json_object) => Ok(Some((config.hash, json_object))),
+        v => {
+            println!("Upstream config isn't object {:?}", v);
+            Err(Response::builder().status(500).body(Body::from("Failed to call upstream config API")).unwrap())
+        },
+    }
+}
 
-    let max_chunk_count = config.value.iter().map(|x| match x {
+fn get_json_number(object: &tl::types::JsonObject, key: &str) -> Result<f64, String> {
+    let value = object.value.iter().map(|x| match x {
         tl::enums::JsonobjectValue::JsonObjectValue(json_object_value) => json_object_value,
-    }).find(|x| { x.key == max_chunk_count_key });
+    }).find(|x| { x.key == key });
 
-    let max_chunk_count = match max_chunk_count {
+    match value {
         Some(v) => match &v.value {
-            tl::enums::Jsonvalue::JsonNull => todo!(),
-            tl::enums::Jsonvalue::JsonBool(json_bool) => todo!(),
-            tl::enums::Jsonvalue::JsonNumber(json_number) => json_number,
-            tl::enums::Jsonvalue::JsonString(json_string) => todo!(),
-            tl::enums::Jsonvalue::JsonArray(json_array) => todo!(),
-            tl::enums::Jsonvalue::JsonObject(json_object) => todo!(),
-        },
-        None => {
-            return Response::builder().status(500).body(Body::from("Failed to get max chunk count from upstream API")).unwrap();
+            tl::enums::Jsonvalue::JsonNumber(json_number) => Ok(json_number.value),
+            v => Err(format!("{} isn't number: {:?}", key, v)),
         },
+        None => Err(format!("{} doesn't exist", key)),
+    }
+}
+
+pub async fn get_upload_limit(client: &Client, limits: &UploadLimitCache) -> Response {
+    let file_size_limit = match limits.file_size_limit(client).await {
+        Ok(v) => v,
+        Err(res) => return res,
     };
 
     let res = UploadLimitResponse {
-        file_size_limit: max_chunk_count.value as usize * CHUNK_SIZE,
+        file_size_limit,
     };
     let res = serde_json::to_vec(&res).unwrap();
 
@@ -85,4 +160,18 @@ pub async fn get_upload_limit(client: &Client) -> Response {
         .header("Content-Type", "application/json")
         .body(Body::from(res))
         .unwrap()
-}
\ No newline at end of file
+}
+
+/// Returns a 413 response if `file_size` exceeds the upload limit.
+pub(crate) async fn check_file_size(client: &Client, limits: &UploadLimitCache, file_size: u64) -> Option<Response> {
+    let file_size_limit = match limits.file_size_limit(client).await {
+        Ok(v) => v,
+        Err(res) => return Some(res),
+    };
+
+    if file_size > file_size_limit as u64 {
+        return Some(Response::builder().status(413).body(Body::from(format!("file_size should be at most {} bytes", file_size_limit))).unwrap());
+    }
+
+    None
+}
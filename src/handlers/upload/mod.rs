@@ -2,8 +2,24 @@ mod limit;
 mod start;
 mod chunk;
 mod finalize;
+mod status;
+mod pipeline;
+mod stream;
+mod form;
+mod tus;
+mod abort;
+mod from_url;
+mod events;
 
-pub use limit::get_upload_limit;
+pub use limit::{get_upload_limit, UploadLimitCache};
 pub use start::{start_upload, StartUploadQueryParams};
 pub use chunk::{upload_chunk, UploadChunkQueryParams};
-pub use finalize::{upload_finalize, UploadFinalizeQueryParams, UploadFinalizeBody};
+pub use finalize::{upload_finalize, UploadFinalizeQueryParams, UploadFinalizeBody, UploadFinalizeResponse};
+pub use status::{get_upload_status, UploadStatusQueryParams};
+pub use stream::{put_file, PutFileQueryParams};
+pub use form::{upload_form, UploadFormQueryParams};
+pub use tus::{tus_create, tus_delete, tus_head, tus_options, tus_patch, TusRegistry};
+pub use abort::{abort_upload, AbortUploadQueryParams};
+pub use from_url::{get_fetch_job, upload_from_url, FetchJobRegistry, UploadFromUrlBody};
+pub use events::{upload_events, UploadEventsQueryParams};
+pub(crate) use finalize::{manifest_text, send_manifest, send_media};
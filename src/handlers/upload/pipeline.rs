@@ -0,0 +1,303 @@
+use std::sync::Arc;
+
+use axum::{body::Body, response::Response};
+use grammers_client::Client;
+use tokio::task::JoinSet;
+
+use crate::{compression, config, dedup::DedupIndex, finalized::FinalizedUploads, proto::{FileCompression, FileEncryption, FileRef, UploadTokenV1}, shared::BIG_UPLOAD_THRESHOLD, transfers::TransferPool, uploads::UploadRegistry};
+
+use super::{chunk::{save_part, SavePartError}, finalize::{finalize_upload, UploadFinalizeBody}, start::new_file_id};
+
+/// How many parts can be sent to upstream at once for a single streaming upload.
+const MAX_PARALLEL_PARTS: usize = 4;
+
+/// Uploads a byte stream of known or unknown length by splitting it into parts.
+///
+/// Memory usage is bounded to about `MAX_PARALLEL_PARTS` parts, plus up to `BIG_UPLOAD_THRESHOLD`
+/// while it's still unknown whether the stream needs a big upload.
+///
+/// If compressed, the stream is cut into frames which are compressed separately, and the compressed
+/// frames are cut into parts again. The stored size is unknown until the end then.
+pub(crate) struct StreamingUpload {
+    client: Client,
+    transfers: Arc<TransferPool>,
+    uploads: Arc<UploadRegistry>,
+    dedup: Arc<DedupIndex>,
+    finalized: Arc<FinalizedUploads>,
+    token: UploadTokenV1,
+    expected_size: Option<u64>,
+    received_size: u64,
+    /// upload limit of the account, checked as bytes arrive since the size may be unknown until the end
+    size_limit: Option<u64>,
+    /// size in upstream, if it's known
+    stored_size: Option<u64>,
+    /// bytes written to `buffer` so far
+    stored_written: u64,
+    /// content of the frame being filled, only if compressed
+    frame: Vec<u8>,
+    next_frame: i32,
+    /// stored offset of every frame written so far, for the seek table
+    frame_offsets: Vec<u64>,
+    /// the last part is always kept here, since big uploads need the total part count with it
+    buffer: Vec<u8>,
+    next_part: i32,
+    /// `None` until we know whether `SaveBigFilePart` is needed (only if the size is unknown)
+    big: Option<bool>,
+    /// parts waiting for `big` to be decided
+    held: Vec<Vec<u8>>,
+    tasks: JoinSet<Result<(), SavePartError>>,
+}
+
+impl StreamingUpload {
+    pub fn new(client: Client, transfers: Arc<TransferPool>, uploads: Arc<UploadRegistry>, dedup: Arc<DedupIndex>, finalized: Arc<FinalizedUploads>, expected_size: Option<u64>, thumbnail: bool, encryption: Option<FileEncryption>, compression: Option<FileCompression>) -> StreamingUpload {
+        let stored_size = match compression {
+            Some(_) => None,
+            None => expected_size,
+        };
+        let mut token = UploadTokenV1 {
+            file_id: new_file_id(),
+            // for unknown size, this is updated at finish, but the session keeps the initial value
+            file_size: stored_size.unwrap_or(0) as i64,
+            thumbnail,
+            segment_size: 0,
+            encryption,
+            compression,
+            part_size: 0,
+        };
+        token.set_part_size(config::get().upload_chunk_size);
+        uploads.with_session(&token, |_| {});
+
+        StreamingUpload {
+            client,
+            transfers,
+            uploads,
+            dedup,
+            finalized,
+            big: stored_size.map(|_| token.should_use_big_upload()),
+            token,
+            expected_size,
+            received_size: 0,
+            size_limit: None,
+            stored_size,
+            stored_written: 0,
+            frame: vec![],
+            next_frame: 0,
+            frame_offsets: vec![],
+            buffer: Vec::with_capacity(token.chunk_size()),
+            next_part: 0,
+            held: vec![],
+            tasks: JoinSet::new(),
+        }
+    }
+
+    pub fn token(&self) -> &UploadTokenV1 {
+        &self.token
+    }
+
+    /// Makes `write` fail with 413 once the stored size exceeds `size_limit`.
+    pub fn set_size_limit(&mut self, size_limit: u64) {
+        self.size_limit = Some(size_limit);
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), Response> {
+        self.received_size += data.len() as u64;
+        if let Some(expected_size) = self.expected_size {
+            if self.received_size > expected_size {
+                return Err(Response::builder().status(400).body(Body::from("body is longer than the expected size")).unwrap());
+            }
+        }
+
+        let frame_size = match &self.token.compression {
+            Some(compression) => compression.frame_size as usize,
+            None => return self.write_stored(data).await,
+        };
+        self.frame.extend_from_slice(data);
+        while self.frame.len() >= frame_size {
+            let rest = self.frame.split_off(frame_size);
+            let frame = std::mem::replace(&mut self.frame, rest);
+            self.push_frame(frame).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn write_stored(&mut self, data: &[u8]) -> Result<(), Response> {
+        self.stored_written += data.len() as u64;
+        if let Some(size_limit) = self.size_limit {
+            if self.stored_written > size_limit {
+                return Err(Response::builder().status(413).body(Body::from(format!("file_size should be at most {} bytes", size_limit))).unwrap());
+            }
+        }
+        self.buffer.extend_from_slice(data);
+        let chunk_size = self.token.chunk_size();
+        while self.buffer.len() > chunk_size {
+            let rest = self.buffer.split_off(chunk_size);
+            let part = std::mem::replace(&mut self.buffer, rest);
+            self.push_part(part).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn push_frame(&mut self, content: Vec<u8>) -> Result<(), Response> {
+        let frame = self.next_frame;
+        self.next_frame += 1;
+        self.uploads.with_session(&self.token, |s| s.record_content(frame, &content));
+
+        self.frame_offsets.push(self.stored_written);
+        // compressing a frame takes a while, so it's done off the async threads, like thumbnails
+        let compressed = match tokio::task::spawn_blocking(move || compression::compress_frame(&content)).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("failed to compress a frame: {:?}", e);
+                return Err(Response::builder().status(500).body(Body::from("failed to compress the file")).unwrap());
+            }
+        };
+        self.write_stored(&compressed).await
+    }
+
+    /// Writes the last frame and the seek table, and fills the sizes in the token.
+    async fn finish_compression(&mut self) -> Result<(), Response> {
+        if !self.frame.is_empty() {
+            let frame = std::mem::take(&mut self.frame);
+            self.push_frame(frame).await?;
+        }
+
+        let seek_table_offset = self.stored_written;
+        let mut offsets = std::mem::take(&mut self.frame_offsets);
+        offsets.push(seek_table_offset);
+        self.write_stored(&compression::seek_table_frame(&offsets)).await?;
+
+        if let Some(compression) = &mut self.token.compression {
+            compression.original_size = self.received_size as i64;
+            compression.seek_table_offset = seek_table_offset as i64;
+        }
+        Ok(())
+    }
+
+    /// Sends the remaining parts and finalizes the upload.
+    pub async fn finish(mut self, name: String, content_type: Option<String>) -> Result<FileRef, Response> {
+        if let Some(expected_size) = self.expected_size {
+            if self.received_size != expected_size {
+                self.abort();
+                return Err(Response::builder().status(400).body(Body::from("body is shorter than the expected size")).unwrap());
+            }
+        }
+        if self.received_size == 0 {
+            self.abort();
+            return Err(Response::builder().status(400).body(Body::from("empty body")).unwrap());
+        }
+
+        if self.token.compression.is_some() {
+            if let Err(res) = self.finish_compression().await {
+                self.abort();
+                return Err(res);
+            }
+        }
+
+        // now the size is known, so the last part can be sent with the correct total part count
+        self.stored_size = Some(self.stored_written);
+        self.token.file_size = self.stored_written as i64;
+        self.big = Some(self.token.should_use_big_upload());
+
+        let mut parts = std::mem::take(&mut self.held);
+        parts.push(std::mem::take(&mut self.buffer));
+        for part in parts {
+            if let Err(res) = self.spawn_part(part).await {
+                self.abort();
+                return Err(res);
+            }
+        }
+        if let Err(res) = self.flush().await {
+            self.abort();
+            return Err(res);
+        }
+
+        let body = UploadFinalizeBody {
+            md5: "".to_string(),
+            sha256: None,
+            name,
+            content_type,
+            metadata: None,
+            media: None,
+        };
+        let res = finalize_upload(&self.client, &self.uploads, &self.dedup, &self.finalized, &self.token, body).await;
+        if res.is_err() {
+            self.abort();
+        }
+        res
+    }
+
+    /// Waits for in-flight parts, so that everything written except the last part is stored in upstream.
+    pub async fn flush(&mut self) -> Result<(), Response> {
+        while !self.tasks.is_empty() {
+            self.join_one().await?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the upload. In-flight parts are cancelled when this is dropped.
+    pub fn abort(&self) {
+        self.uploads.remove(self.token.file_id);
+    }
+
+    async fn push_part(&mut self, part: Vec<u8>) -> Result<(), Response> {
+        if self.big.is_some() {
+            return self.spawn_part(part).await;
+        }
+
+        self.held.push(part);
+        // the buffer still has at least one byte, so the file is larger than this
+        if (self.held.len() * self.token.part_size()) as i64 >= BIG_UPLOAD_THRESHOLD {
+            self.big = Some(true);
+            for part in std::mem::take(&mut self.held) {
+                self.spawn_part(part).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn spawn_part(&mut self, bytes: Vec<u8>) -> Result<(), Response> {
+        while self.tasks.len() >= MAX_PARALLEL_PARTS {
+            self.join_one().await?;
+        }
+
+        let part = self.next_part;
+        self.next_part += 1;
+
+        let big = self.big.unwrap_or(false);
+        // upstream accepts -1 as the total part count while it's unknown
+        let total_parts = match self.stored_size {
+            Some(_) => self.token.total_parts(),
+            None => -1,
+        };
+
+        let transfers = self.transfers.clone();
+        let uploads = self.uploads.clone();
+        let token = self.token.clone();
+        self.tasks.spawn(async move {
+            save_part(&transfers, &uploads, &token, big, total_parts, part, bytes).await
+        });
+
+        Ok(())
+    }
+
+    async fn join_one(&mut self) -> Result<(), Response> {
+        match self.tasks.join_next().await {
+            None | Some(Ok(Ok(()))) => Ok(()),
+            Some(Ok(Err(SavePartError::Encryption))) => {
+                println!("failed to encrypt a part of {}", self.token.file_id);
+                Err(Response::builder().status(500).body(Body::from("failed to encrypt the file")).unwrap())
+            },
+            Some(Ok(Err(SavePartError::Upstream(e)))) => {
+                println!("failed to call upstream api {:?}", e);
+                Err(Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap())
+            },
+            Some(Err(e)) => {
+                println!("upload task failed {:?}", e);
+                Err(Response::builder().status(500).body(Body::from("failed to call upstream api")).unwrap())
+            },
+        }
+    }
+}
@@ -1,11 +1,29 @@
 use axum::{body::Body, response::Response};
+use grammers_client::Client;
 use rand::{rngs::StdRng, RngCore, SeedableRng};
 
-use crate::{proto::{UploadToken, UploadTokenV1}, shared::CHUNK_SIZE};
+use crate::{compression, config, crypto, proto::{FileCompression, FileEncryption, UploadToken, UploadTokenV1}, shared::MIN_PART_SIZE, uploads::UploadRegistry};
+
+use super::limit::UploadLimitCache;
+
+/// Files larger than the per-file limit are split into at most this many documents.
+const MAX_SEGMENTS: u64 = 64;
 
 #[derive(serde::Deserialize)]
 pub struct StartUploadQueryParams {
     file_size: u64,
+    /// generate a thumbnail at finalize, if the file is an image
+    #[serde(default)]
+    thumbnail: bool,
+    /// `aes-256-gcm` or `chacha20-poly1305` to encrypt the file at rest
+    #[serde(default)]
+    encryption: Option<String>,
+    /// max chunk size which the client wants, the server may pick a smaller one
+    #[serde(default)]
+    chunk_size: Option<usize>,
+    /// only to reject it, since chunks are stored as they are sent
+    #[serde(default)]
+    compression: Option<String>,
 }
 
 #[derive(serde::Serialize)]
@@ -14,17 +32,103 @@ pub struct StartUploadResponse {
     chunk_size: usize,
 }
 
-pub async fn start_upload(query: StartUploadQueryParams) -> Response {
+pub(crate) fn new_file_id() -> i64 {
     let file_id = StdRng::from_entropy().next_u64();
+    (file_id as i64).abs()
+}
+
+/// Generates a file key if encryption is requested.
+pub(crate) fn parse_encryption(input: Option<&str>, thumbnail: bool) -> Result<Option<FileEncryption>, Response> {
+    let input = match input {
+        None | Some("") | Some("none") => return Ok(None),
+        Some(v) => v,
+    };
+    let cipher = match crypto::parse_cipher(input) {
+        Some(v) => v,
+        None => {
+            return Err(Response::builder().status(400).body(Body::from("encryption should be aes-256-gcm or chacha20-poly1305")).unwrap());
+        }
+    };
+    if thumbnail {
+        return Err(Response::builder().status(400).body(Body::from("thumbnail can't be used for encrypted files")).unwrap());
+    }
+    if config::get().secret_key.is_none() {
+        return Err(Response::builder().status(400).body(Body::from("encryption is unavailable since TELETON_SECRET_KEY is not configured")).unwrap());
+    }
+    Ok(Some(crypto::new_file_encryption(cipher)))
+}
+
+/// Returns the compression settings if compression is requested. Only streaming uploads support it, chunked uploads reject it.
+pub(crate) fn parse_compression(input: Option<&str>, encrypted: bool) -> Result<Option<FileCompression>, Response> {
+    let input = match input {
+        None | Some("") | Some("none") => return Ok(None),
+        Some(v) => v,
+    };
+    let algorithm = match compression::parse_compression(input) {
+        Some(v) => v,
+        None => {
+            return Err(Response::builder().status(400).body(Body::from("compression should be zstd")).unwrap());
+        }
+    };
+    if encrypted {
+        return Err(Response::builder().status(400).body(Body::from("compression can't be used for encrypted files")).unwrap());
+    }
+    Ok(Some(compression::new_file_compression(algorithm)))
+}
+
+pub async fn start_upload(client: &Client, uploads: &UploadRegistry, limits: &UploadLimitCache, query: StartUploadQueryParams) -> Response {
+    if query.file_size == 0 {
+        return Response::builder().status(400).body(Body::from("file_size should be positive")).unwrap();
+    }
+
+    if !matches!(query.compression.as_deref(), None | Some("") | Some("none")) {
+        return Response::builder().status(400).body(Body::from("compression is only supported by streaming uploads (PUT /v1/upload, form, from-url and tus)")).unwrap();
+    }
+
+    let encryption = match parse_encryption(query.encryption.as_deref(), query.thumbnail) {
+        Ok(v) => v,
+        Err(res) => return res,
+    };
 
-    let token = UploadTokenV1 {
-        file_id: (file_id as i64).abs(),
+    let max_part_count = match limits.max_part_count(client).await {
+        Ok(v) => v as u64,
+        Err(res) => return res,
+    };
+
+    let mut token = UploadTokenV1 {
+        file_id: new_file_id(),
         file_size: query.file_size as i64,
+        thumbnail: query.thumbnail,
+        segment_size: 0,
+        encryption,
+        compression: None,
+        part_size: 0,
     };
 
+    // the largest part size which is not larger than the configured one nor the requested one
+    let mut part_size = config::get().upload_chunk_size;
+    token.set_part_size(part_size);
+    if let Some(requested) = query.chunk_size {
+        while token.chunk_size() > requested && part_size > MIN_PART_SIZE {
+            part_size /= 2;
+            token.set_part_size(part_size);
+        }
+    }
+
+    // larger files are stored as several documents, each of which fits in the limit
+    let max_segment_size = max_part_count * token.chunk_size() as u64;
+    if token.total_parts() as u64 > max_part_count {
+        if query.file_size.div_ceil(max_segment_size) > MAX_SEGMENTS {
+            return Response::builder().status(413).body(Body::from(format!("file_size should be at most {} bytes", max_segment_size * MAX_SEGMENTS))).unwrap();
+        }
+        token.segment_size = max_segment_size as i64;
+    }
+
+    uploads.with_session(&token, |_| {});
+
     let body = StartUploadResponse {
-        token: UploadToken { v1: Some(token) }.to_api_string(),
-        chunk_size: CHUNK_SIZE,
+        chunk_size: token.chunk_size(),
+        token: UploadToken { v1: Some(token), ..Default::default() }.to_api_string(),
     };
 
     let body = serde_json::to_vec(&body).unwrap();
@@ -0,0 +1,78 @@
+use axum::{body::Body, response::Response};
+
+use crate::{proto::UploadToken, uploads::UploadRegistry};
+
+#[derive(serde::Deserialize)]
+pub struct UploadStatusQueryParams {
+    token: String,
+}
+
+#[derive(serde::Serialize)]
+struct ByteRange {
+    start: u64,
+    /// exclusive
+    end: u64,
+}
+
+#[derive(serde::Serialize)]
+struct UploadStatusResponse {
+    file_size: i64,
+    chunk_size: usize,
+    total_parts: i32,
+    received_offsets: Vec<u64>,
+    missing_ranges: Vec<ByteRange>,
+}
+
+pub async fn get_upload_status(uploads: &UploadRegistry, query: UploadStatusQueryParams) -> Response {
+    let token = UploadToken::from_api_string(query.token);
+
+    let token = match token {
+        None => {
+            return Response::builder().status(400).body(Body::from("invalid token")).unwrap();
+        }
+        Some(t) => t,
+    };
+
+    let token = match token.v1 {
+        None => {
+            return Response::builder().status(400).body(Body::from("invalid token")).unwrap();
+        }
+        Some(t) => t,
+    };
+
+    if uploads.is_aborted(token.file_id) {
+        return Response::builder().status(410).body(Body::from("upload is aborted")).unwrap();
+    }
+
+    let received = uploads.received_parts(token.file_id);
+
+    let file_size = token.file_size as u64;
+    let chunk_size = token.chunk_size() as u64;
+    let mut missing_ranges: Vec<ByteRange> = vec![];
+    for part in 0..token.total_parts() {
+        if received.contains(&part) {
+            continue;
+        }
+        let start = part as u64 * chunk_size;
+        let end = u64::min(start + chunk_size, file_size);
+        match missing_ranges.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => missing_ranges.push(ByteRange { start, end }),
+        }
+    }
+
+    let res = UploadStatusResponse {
+        file_size: token.file_size,
+        chunk_size: token.chunk_size(),
+        total_parts: token.total_parts(),
+        received_offsets: received.iter().map(|x| *x as u64 * chunk_size).collect(),
+        missing_ranges,
+    };
+    let res = serde_json::to_vec(&res).unwrap();
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(res))
+        .unwrap()
+}
@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use axum::{body::Body, http::HeaderMap, response::Response};
+use futures_util::StreamExt;
+use grammers_client::Client;
+
+use crate::{dedup::DedupIndex, finalized::FinalizedUploads, transfers::TransferPool, uploads::UploadRegistry};
+
+use super::{finalize::UploadFinalizeResponse, start::{parse_compression, parse_encryption}, limit::{check_file_size, UploadLimitCache}, pipeline::StreamingUpload};
+
+#[derive(serde::Deserialize)]
+pub struct PutFileQueryParams {
+    name: String,
+    /// generate a thumbnail, if the file is an image
+    #[serde(default)]
+    thumbnail: bool,
+    /// `aes-256-gcm` or `chacha20-poly1305` to encrypt the file at rest
+    #[serde(default)]
+    encryption: Option<String>,
+    /// `zstd` to store the file compressed
+    #[serde(default)]
+    compression: Option<String>,
+}
+
+pub async fn put_file(client: &Client, transfers: &Arc<TransferPool>, uploads: &Arc<UploadRegistry>, dedup: &Arc<DedupIndex>, finalized: &Arc<FinalizedUploads>, limits: &UploadLimitCache, query: PutFileQueryParams, headers: &HeaderMap, body: Body) -> Response {
+    // without Content-Length (chunked transfer), the size is determined when the body ends
+    let expected_size = match headers.get("Content-Length") {
+        None => None,
+        Some(v) => match v.to_str().ok().and_then(|v| v.parse::<u64>().ok()) {
+            Some(v) => Some(v),
+            None => {
+                return Response::builder().status(400).body(Body::from("invalid Content-Length")).unwrap();
+            }
+        },
+    };
+
+    if let Some(expected_size) = expected_size {
+        if let Some(res) = check_file_size(client, limits, expected_size).await {
+            return res;
+        }
+    }
+    // chunked bodies are checked while they are read
+    let file_size_limit = match limits.file_size_limit(client).await {
+        Ok(v) => v as u64,
+        Err(res) => return res,
+    };
+
+    let encryption = match parse_encryption(query.encryption.as_deref(), query.thumbnail) {
+        Ok(v) => v,
+        Err(res) => return res,
+    };
+    let compression = match parse_compression(query.compression.as_deref(), encryption.is_some()) {
+        Ok(v) => v,
+        Err(res) => return res,
+    };
+
+    let mut upload = StreamingUpload::new(client.clone(), transfers.clone(), uploads.clone(), dedup.clone(), finalized.clone(), expected_size, query.thumbnail, encryption, compression);
+    upload.set_size_limit(file_size_limit);
+
+    let mut body = body.into_data_stream();
+    while let Some(data) = body.next().await {
+        let data = match data {
+            Ok(v) => v,
+            Err(e) => {
+                println!("failed to read request body {:?}", e);
+                upload.abort();
+                return Response::builder().status(400).body(Body::from("failed to read request body")).unwrap();
+            }
+        };
+        if let Err(res) = upload.write(&data).await {
+            upload.abort();
+            return res;
+        }
+    }
+
+    let content_type = headers.get("Content-Type").and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+
+    let file_ref = match upload.finish(query.name, content_type).await {
+        Ok(v) => v,
+        Err(res) => return res,
+    };
+
+    let res = UploadFinalizeResponse {
+        r#ref: file_ref.to_ref_string(),
+    };
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(&res).unwrap()))
+        .unwrap()
+}
@@ -0,0 +1,392 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use axum::{body::Body, http::{HeaderMap, HeaderValue}, response::Response};
+use base64::Engine;
+use futures_util::StreamExt;
+use grammers_client::Client;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::{dedup::DedupIndex, finalized::FinalizedUploads, proto::UploadToken, shared::is_valid_content_type, transfers::TransferPool, uploads::UploadRegistry};
+
+use super::{limit::{check_file_size, UploadLimitCache}, pipeline::StreamingUpload, start::parse_compression};
+
+const TUS_VERSION: &str = "1.0.0";
+
+/// Upper bound of a PATCH body with `Upload-Checksum`, since it has to be verified before being applied.
+const MAX_CHECKSUM_PATCH_SIZE: usize = 64 * 1024 * 1024;
+
+struct TusUpload {
+    /// `None` after the upload is finalized
+    upload: Option<StreamingUpload>,
+    length: u64,
+    offset: u64,
+    /// raw `Upload-Metadata` header, returned as is on HEAD
+    metadata: Option<String>,
+    name: String,
+    content_type: Option<String>,
+    file_ref: Option<String>,
+    last_activity: Instant,
+}
+
+/// State of uploads which are started with the tus protocol, keyed by `file_id`.
+///
+/// Unlike the chunk API, this is not recoverable after restart because incomplete parts are buffered in memory.
+#[derive(Default)]
+pub struct TusRegistry {
+    uploads: Mutex<HashMap<i64, Arc<tokio::sync::Mutex<TusUpload>>>>,
+}
+
+impl TusRegistry {
+    pub fn new() -> TusRegistry {
+        TusRegistry::default()
+    }
+
+    fn get(&self, id: &str) -> Option<(i64, Arc<tokio::sync::Mutex<TusUpload>>)> {
+        let token = UploadToken::from_api_string(id.to_string())?.v1?;
+        let upload = self.uploads.lock().unwrap().get(&token.file_id).cloned()?;
+        Some((token.file_id, upload))
+    }
+
+    fn remove(&self, file_id: i64) {
+        self.uploads.lock().unwrap().remove(&file_id);
+    }
+
+    /// Forgets uploads which have been idle longer than `ttl`, and returns how many uploads were forgotten.
+    pub fn expire_idle(&self, ttl: Duration) -> usize {
+        let mut uploads = self.uploads.lock().unwrap();
+        let before = uploads.len();
+        uploads.retain(|_, upload| match upload.try_lock() {
+            Ok(upload) => upload.last_activity.elapsed() < ttl,
+            // someone is using it right now
+            Err(_) => true,
+        });
+        before - uploads.len()
+    }
+}
+
+fn tus_response(status: u16) -> axum::http::response::Builder {
+    Response::builder()
+        .status(status)
+        .header("Tus-Resumable", TUS_VERSION)
+}
+
+fn check_tus_resumable(headers: &HeaderMap) -> Option<Response> {
+    if headers.get("Tus-Resumable").and_then(|v| v.to_str().ok()) == Some(TUS_VERSION) {
+        return None;
+    }
+
+    Some(tus_response(412).header("Tus-Version", TUS_VERSION).body(Body::from("unsupported tus version")).unwrap())
+}
+
+/// Another request of the upload may be stalled on reading its body, so this doesn't wait for it.
+/// tus clients retry on 423 after a while.
+fn try_lock_upload(upload: &tokio::sync::Mutex<TusUpload>) -> Result<tokio::sync::MutexGuard<'_, TusUpload>, Response> {
+    upload.try_lock().map_err(|_| tus_response(423).body(Body::from("another request is in progress for this upload")).unwrap())
+}
+
+fn parse_u64_header(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name).and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok())
+}
+
+/// Parses `Upload-Metadata` (`key base64value,key2 base64value2,...`).
+fn parse_metadata(input: &str) -> HashMap<String, String> {
+    input.split(',').filter_map(|pair| {
+        let mut pair = pair.trim().splitn(2, ' ');
+        let key = pair.next()?.to_string();
+        let value = match pair.next() {
+            Some(v) => base64::prelude::BASE64_STANDARD.decode(v).ok().and_then(|v| String::from_utf8(v).ok())?,
+            None => "".to_string(),
+        };
+        Some((key, value))
+    }).collect()
+}
+
+/// Returns whether the body matches `Upload-Checksum` (`algorithm base64digest`), or `None` if the algorithm isn't supported.
+fn verify_checksum(header: &str, body: &[u8]) -> Option<bool> {
+    let (algorithm, expected) = header.split_once(' ')?;
+    let expected = base64::prelude::BASE64_STANDARD.decode(expected.trim()).ok()?;
+    let actual = match algorithm {
+        "md5" => Md5::digest(body).to_vec(),
+        "sha1" => Sha1::digest(body).to_vec(),
+        "sha256" => Sha256::digest(body).to_vec(),
+        _ => return None,
+    };
+    Some(actual == expected)
+}
+
+pub async fn tus_options() -> Response {
+    tus_response(204)
+        .header("Tus-Version", TUS_VERSION)
+        .header("Tus-Extension", "creation,termination,checksum")
+        .header("Tus-Checksum-Algorithm", "md5,sha1,sha256")
+        .body(Body::empty())
+        .unwrap()
+}
+
+pub async fn tus_create(client: &Client, transfers: &Arc<TransferPool>, uploads: &Arc<UploadRegistry>, dedup: &Arc<DedupIndex>, finalized: &Arc<FinalizedUploads>, limits: &UploadLimitCache, tus: &TusRegistry, headers: &HeaderMap) -> Response {
+    if let Some(res) = check_tus_resumable(headers) {
+        return res;
+    }
+
+    let length = match parse_u64_header(headers, "Upload-Length") {
+        Some(v) if v > 0 => v,
+        Some(_) => {
+            return tus_response(400).body(Body::from("empty uploads are not supported")).unwrap();
+        }
+        None => {
+            // Upload-Defer-Length (creation-defer-length extension) isn't supported
+            return tus_response(400).body(Body::from("Upload-Length is required")).unwrap();
+        }
+    };
+
+    if let Some(mut res) = check_file_size(client, limits, length).await {
+        res.headers_mut().insert("Tus-Resumable", HeaderValue::from_static(TUS_VERSION));
+        return res;
+    }
+
+    let raw_metadata = headers.get("Upload-Metadata").and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+    let metadata = raw_metadata.as_deref().map(parse_metadata).unwrap_or_default();
+    let name = metadata.get("filename").or(metadata.get("name")).cloned().unwrap_or_else(|| "file".to_string());
+    let content_type = metadata.get("filetype").or(metadata.get("type")).cloned();
+    // checked before the upload, since finalize would reject it at the end
+    if content_type.as_deref().is_some_and(|x| !is_valid_content_type(x)) {
+        return tus_response(400).body(Body::from("filetype should be a valid header value")).unwrap();
+    }
+
+    let compression = match parse_compression(metadata.get("compression").map(|x| x.as_str()), false) {
+        Ok(v) => v,
+        Err(mut res) => {
+            res.headers_mut().insert("Tus-Resumable", HeaderValue::from_static(TUS_VERSION));
+            return res;
+        }
+    };
+    let upload = StreamingUpload::new(client.clone(), transfers.clone(), uploads.clone(), dedup.clone(), finalized.clone(), Some(length), false, None, compression);
+    let token = upload.token().clone();
+    tus.uploads.lock().unwrap().insert(token.file_id, Arc::new(tokio::sync::Mutex::new(TusUpload {
+        upload: Some(upload),
+        length,
+        offset: 0,
+        metadata: raw_metadata,
+        name,
+        content_type,
+        file_ref: None,
+        last_activity: Instant::now(),
+    })));
+
+    tus_response(201)
+        .header("Location", format!("/v1/tus/{}", UploadToken { v1: Some(token), ..Default::default() }.to_api_string()))
+        .body(Body::empty())
+        .unwrap()
+}
+
+pub async fn tus_head(tus: &TusRegistry, headers: &HeaderMap, id: String) -> Response {
+    if let Some(res) = check_tus_resumable(headers) {
+        return res;
+    }
+
+    let upload = match tus.get(&id) {
+        Some((_, v)) => v,
+        None => {
+            return tus_response(404).body(Body::empty()).unwrap();
+        }
+    };
+    let mut upload = match try_lock_upload(&upload) {
+        Ok(v) => v,
+        Err(res) => return res,
+    };
+    upload.last_activity = Instant::now();
+
+    let mut res = tus_response(200)
+        .header("Cache-Control", "no-store")
+        .header("Upload-Offset", upload.offset)
+        .header("Upload-Length", upload.length);
+    if let Some(metadata) = &upload.metadata {
+        res = res.header("Upload-Metadata", metadata);
+    }
+    if let Some(file_ref) = &upload.file_ref {
+        res = res.header("X-Teleton-Ref", file_ref);
+    }
+    res.body(Body::empty()).unwrap()
+}
+
+pub async fn tus_patch(tus: &TusRegistry, headers: &HeaderMap, id: String, body: Body) -> Response {
+    if let Some(res) = check_tus_resumable(headers) {
+        return res;
+    }
+
+    if headers.get("Content-Type").and_then(|v| v.to_str().ok()) != Some("application/offset+octet-stream") {
+        return tus_response(415).body(Body::from("Content-Type should be application/offset+octet-stream")).unwrap();
+    }
+
+    let (file_id, upload) = match tus.get(&id) {
+        Some(v) => v,
+        None => {
+            return tus_response(404).body(Body::empty()).unwrap();
+        }
+    };
+    let mut upload = match try_lock_upload(&upload) {
+        Ok(v) => v,
+        Err(res) => return res,
+    };
+    upload.last_activity = Instant::now();
+
+    if parse_u64_header(headers, "Upload-Offset") != Some(upload.offset) {
+        return tus_response(409).header("Upload-Offset", upload.offset).body(Body::from("Upload-Offset mismatch")).unwrap();
+    }
+
+    let checksum = headers.get("Upload-Checksum").and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+
+    let TusUpload { upload: streaming, offset, .. } = &mut *upload;
+    let streaming = match streaming {
+        Some(v) => v,
+        None => {
+            return tus_response(409).header("Upload-Offset", *offset).body(Body::from("upload is already completed")).unwrap();
+        }
+    };
+
+    let mut body = body.into_data_stream();
+    let res = match checksum {
+        // the body should be discarded if the checksum doesn't match, so it can't be streamed
+        Some(checksum) => {
+            let mut data = vec![];
+            while let Some(chunk) = body.next().await {
+                match chunk {
+                    Ok(chunk) if data.len() + chunk.len() <= MAX_CHECKSUM_PATCH_SIZE => data.extend_from_slice(&chunk),
+                    Ok(_) => {
+                        return tus_response(413).body(Body::from(format!("PATCH with checksum should be smaller than {} bytes", MAX_CHECKSUM_PATCH_SIZE))).unwrap();
+                    },
+                    Err(e) => {
+                        println!("failed to read request body {:?}", e);
+                        return tus_response(400).body(Body::from("failed to read request body")).unwrap();
+                    },
+                }
+            }
+            match verify_checksum(&checksum, &data) {
+                None => {
+                    return tus_response(400).body(Body::from("unsupported checksum algorithm")).unwrap();
+                },
+                Some(false) => {
+                    return tus_response(460).body(Body::from("checksum mismatch")).unwrap();
+                },
+                Some(true) => {},
+            }
+            let res = streaming.write(&data).await;
+            if res.is_ok() {
+                *offset += data.len() as u64;
+            }
+            res
+        },
+        None => {
+            let mut res = Ok(());
+            while let Some(chunk) = body.next().await {
+                let chunk = match chunk {
+                    Ok(v) => v,
+                    Err(e) => {
+                        // bytes which are already written are kept, the client can resume from the new offset
+                        println!("failed to read request body {:?}", e);
+                        break;
+                    }
+                };
+                if let Err(e) = streaming.write(&chunk).await {
+                    res = Err(e);
+                    break;
+                }
+                *offset += chunk.len() as u64;
+            }
+            res
+        },
+    };
+    let res = match res {
+        Ok(()) => streaming.flush().await,
+        Err(e) => Err(e),
+    };
+    if let Err(res) = res {
+        // parts may be lost, so this upload can't be continued anymore
+        streaming.abort();
+        tus.remove(file_id);
+        return res;
+    }
+
+    if upload.offset < upload.length {
+        return tus_response(204).header("Upload-Offset", upload.offset).body(Body::empty()).unwrap();
+    }
+
+    let streaming = upload.upload.take().unwrap();
+    let file_ref = match streaming.finish(upload.name.clone(), upload.content_type.clone()).await {
+        Ok(v) => v.to_ref_string(),
+        Err(res) => {
+            tus.remove(file_id);
+            return res;
+        }
+    };
+    upload.file_ref = Some(file_ref.clone());
+
+    tus_response(204)
+        .header("Upload-Offset", upload.offset)
+        .header("X-Teleton-Ref", file_ref)
+        .body(Body::empty())
+        .unwrap()
+}
+
+pub async fn tus_delete(tus: &TusRegistry, headers: &HeaderMap, id: String) -> Response {
+    if let Some(res) = check_tus_resumable(headers) {
+        return res;
+    }
+
+    let (file_id, upload) = match tus.get(&id) {
+        Some(v) => v,
+        None => {
+            return tus_response(404).body(Body::empty()).unwrap();
+        }
+    };
+    let mut upload = match try_lock_upload(&upload) {
+        Ok(v) => v,
+        Err(res) => return res,
+    };
+    if let Some(streaming) = upload.upload.take() {
+        streaming.abort();
+    }
+    tus.remove(file_id);
+
+    tus_response(204).body(Body::empty()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata() {
+        let metadata = parse_metadata("filename cmVwb3J0LnBkZg==, is_confidential,name 5YaZ55yfLmpwZw==");
+        assert_eq!(metadata.get("filename").map(|x| x.as_str()), Some("report.pdf"));
+        assert_eq!(metadata.get("is_confidential").map(|x| x.as_str()), Some(""));
+        assert_eq!(metadata.get("name").map(|x| x.as_str()), Some("写真.jpg"));
+        assert_eq!(metadata.len(), 3);
+    }
+
+    #[test]
+    fn malformed_metadata_is_skipped() {
+        // not base64, and not UTF-8
+        let metadata = parse_metadata("filename !!!,name /w==,type dGV4dA==");
+        assert_eq!(metadata.len(), 1);
+        assert_eq!(metadata.get("type").map(|x| x.as_str()), Some("text"));
+        assert!(parse_metadata("").get("filename").is_none());
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(verify_checksum("md5 XrY7u+Ae7tCTyyK7j1rNww==", b"hello world"), Some(true));
+        assert_eq!(verify_checksum("sha1 Kq5sNclPz7QV2+lfQIuc6R7oRu0=", b"hello world"), Some(true));
+        assert_eq!(verify_checksum("sha256 uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=", b"hello world"), Some(true));
+        assert_eq!(verify_checksum("sha256 uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=", b"hello world!"), Some(false));
+    }
+
+    #[test]
+    fn unsupported_checksums() {
+        assert_eq!(verify_checksum("crc32 AAAAAA==", b"hello world"), None);
+        assert_eq!(verify_checksum("sha256", b"hello world"), None);
+        assert_eq!(verify_checksum("sha256 !!!", b"hello world"), None);
+    }
+}
@@ -0,0 +1,312 @@
+use base64::Engine;
+use grammers_client::Client;
+use prost::Message;
+
+use crate::{compression, crypto, proto::{CompositeFileRefV1, FileRef, FileRefV1, UploadToken, UploadTokenV1}, shared::{get_message, is_valid_part_size, message_to_file_ref, to_hex}};
+
+#[derive(serde::Serialize)]
+pub struct RefInspection {
+    /// `file_ref`, `composite_file_ref`, `upload_token` or `invalid`
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_ref: Option<FileRefDetails>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    composite_file_ref: Option<CompositeFileRefDetails>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upload_token: Option<UploadTokenDetails>,
+    problems: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    live: Option<LiveStatus>,
+}
+
+#[derive(serde::Serialize)]
+struct FileRefDetails {
+    message_id: i32,
+    document_id: i64,
+    access_hash: i64,
+    file_reference: String,
+    file_size: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    /// cipher name, the key itself is never shown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encryption: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<CompressionDetails>,
+}
+
+#[derive(serde::Serialize)]
+struct CompressionDetails {
+    algorithm: &'static str,
+    original_size: i64,
+    frame_size: i64,
+    seek_table_offset: i64,
+}
+
+#[derive(serde::Serialize)]
+struct CompositeFileRefDetails {
+    manifest_message_id: i32,
+    file_size: i64,
+    segment_size: i64,
+    segments: Vec<FileRefDetails>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encryption: Option<&'static str>,
+}
+
+#[derive(serde::Serialize)]
+struct UploadTokenDetails {
+    file_id: i64,
+    file_size: i64,
+    total_parts: i32,
+    part_size: usize,
+    big_upload: bool,
+    /// only for files larger than the per-file limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    segment_size: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encryption: Option<&'static str>,
+}
+
+#[derive(serde::Serialize)]
+struct LiveStatus {
+    message_exists: bool,
+    document_matches: bool,
+    file_reference_stale: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refreshed_ref: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn invalid(problem: String) -> RefInspection {
+    RefInspection {
+        kind: "invalid",
+        file_ref: None,
+        composite_file_ref: None,
+        upload_token: None,
+        problems: vec![problem],
+        live: None,
+    }
+}
+
+/// Decodes a `FileRef` or `UploadToken` string. If `client` is given, file refs are also checked against the live message.
+pub async fn inspect(input: &str, client: Option<&Client>) -> RefInspection {
+    let decoded = match base64::prelude::BASE64_URL_SAFE_NO_PAD.decode(input) {
+        Ok(v) => v,
+        Err(e) => return invalid(format!("not a valid base64url string: {}", e)),
+    };
+
+    // both messages decode from the same bytes since their first fields share wire types,
+    // but only FileRefV1 has file_reference/access_hash, so use those to tell them apart
+    // UploadToken.sealed has the same tag as FileRef.composite_v1, so only the authenticated unseal can tell them apart
+    let sealed_token = UploadToken::decode(&decoded[..]).ok()
+        .and_then(|x| crypto::unseal(&x.sealed))
+        .and_then(|x| UploadToken::decode(&x[..]).ok())
+        .and_then(|x| x.v1);
+    if let Some(token) = sealed_token {
+        return inspect_upload_token(token);
+    }
+
+    let file_ref = match FileRef::decode(&decoded[..]) {
+        Ok(v) if !v.sealed.is_empty() => match crypto::unseal(&v.sealed) {
+            Some(v) => FileRef::decode(&v[..]).ok(),
+            None => return invalid("sealed with the server key, but TELETON_SECRET_KEY is missing or different".to_string()),
+        },
+        v => v.ok(),
+    };
+    if let Some(composite) = file_ref.as_ref().and_then(|x| x.composite_v1.clone()) {
+        return inspect_composite_file_ref(composite);
+    }
+    let file_ref = file_ref.and_then(|x| x.v1);
+    let upload_token = UploadToken::decode(&decoded[..]).ok().and_then(|x| x.v1);
+
+    match (file_ref, upload_token) {
+        (Some(file_ref), _) if !file_ref.file_reference.is_empty() || file_ref.access_hash != 0 => {
+            inspect_file_ref(file_ref, client).await
+        },
+        (_, Some(upload_token)) => inspect_upload_token(upload_token),
+        _ => invalid("neither FileRef nor UploadToken (or missing v1)".to_string()),
+    }
+}
+
+async fn inspect_file_ref(file_ref: FileRefV1, client: Option<&Client>) -> RefInspection {
+    let mut problems = vec![];
+    if file_ref.message_id <= 0 {
+        problems.push(format!("message_id should be positive (got {})", file_ref.message_id));
+    }
+    if file_ref.document_id == 0 {
+        problems.push("document_id is missing".to_string());
+    }
+    if file_ref.file_reference.is_empty() {
+        problems.push("file_reference is empty".to_string());
+    }
+    if file_ref.file_size <= 0 {
+        problems.push(format!("file_size should be positive (got {})", file_ref.file_size));
+    }
+
+    let live = match client {
+        Some(client) => Some(lookup_live(client, &file_ref).await),
+        None => None,
+    };
+
+    RefInspection {
+        kind: "file_ref",
+        file_ref: Some(file_ref_details(&file_ref)),
+        composite_file_ref: None,
+        upload_token: None,
+        problems,
+        live,
+    }
+}
+
+fn file_ref_details(file_ref: &FileRefV1) -> FileRefDetails {
+    FileRefDetails {
+        message_id: file_ref.message_id,
+        document_id: file_ref.document_id,
+        access_hash: file_ref.access_hash,
+        file_reference: to_hex(&file_ref.file_reference),
+        file_size: file_ref.file_size,
+        sha256: if file_ref.sha256.is_empty() { None } else { Some(to_hex(&file_ref.sha256)) },
+        encryption: file_ref.encryption.as_ref().map(crypto::cipher_name),
+        compression: file_ref.compression.as_ref().map(|x| CompressionDetails {
+            algorithm: compression::compression_name(x),
+            original_size: x.original_size,
+            frame_size: x.frame_size,
+            seek_table_offset: x.seek_table_offset,
+        }),
+    }
+}
+
+/// Live lookup isn't supported for composite refs yet.
+fn inspect_composite_file_ref(file_ref: CompositeFileRefV1) -> RefInspection {
+    let mut problems = vec![];
+    if file_ref.segment_size <= 0 {
+        problems.push(format!("segment_size should be positive (got {})", file_ref.segment_size));
+    } else {
+        let expected = (file_ref.file_size + file_ref.segment_size - 1) / file_ref.segment_size;
+        if file_ref.segments.len() as i64 != expected {
+            problems.push(format!("expected {} segments for file_size, but got {}", expected, file_ref.segments.len()));
+        }
+    }
+    let total: i64 = file_ref.segments.iter().map(|x| x.file_size).sum();
+    if total != file_ref.file_size {
+        problems.push(format!("segments have {} bytes in total, but file_size is {}", total, file_ref.file_size));
+    }
+
+    RefInspection {
+        kind: "composite_file_ref",
+        file_ref: None,
+        composite_file_ref: Some(CompositeFileRefDetails {
+            manifest_message_id: file_ref.manifest_message_id,
+            file_size: file_ref.file_size,
+            segment_size: file_ref.segment_size,
+            segments: file_ref.segments.iter().map(file_ref_details).collect(),
+            sha256: if file_ref.sha256.is_empty() { None } else { Some(to_hex(&file_ref.sha256)) },
+            encryption: file_ref.encryption.as_ref().map(crypto::cipher_name),
+        }),
+        upload_token: None,
+        problems,
+        live: None,
+    }
+}
+
+fn inspect_upload_token(token: UploadTokenV1) -> RefInspection {
+    let mut problems = vec![];
+    if token.file_id <= 0 {
+        problems.push(format!("file_id should be positive (got {})", token.file_id));
+    }
+    if token.file_size <= 0 {
+        problems.push(format!("file_size should be positive (got {})", token.file_size));
+    }
+    if !is_valid_part_size(token.part_size()) {
+        problems.push(format!("part_size should be a power of two from 4096 to 524288 (got {})", token.part_size()));
+    }
+
+    RefInspection {
+        kind: "upload_token",
+        file_ref: None,
+        composite_file_ref: None,
+        upload_token: Some(UploadTokenDetails {
+            file_id: token.file_id,
+            file_size: token.file_size,
+            total_parts: token.total_parts(),
+            part_size: token.part_size(),
+            big_upload: token.should_use_big_upload(),
+            segment_size: if token.is_composite() { Some(token.segment_size) } else { None },
+            encryption: token.encryption.as_ref().map(crypto::cipher_name),
+        }),
+        problems,
+        live: None,
+    }
+}
+
+async fn lookup_live(client: &Client, file_ref: &FileRefV1) -> LiveStatus {
+    let message = match get_message(client, file_ref.peer.as_ref(), file_ref.message_id).await {
+        Ok(v) => v,
+        Err(e) => {
+            return LiveStatus {
+                message_exists: false,
+                document_matches: false,
+                file_reference_stale: false,
+                refreshed_ref: None,
+                error: Some(format!("failed to get message: {}", e)),
+            };
+        }
+    };
+
+    let current = message.as_ref().and_then(message_to_file_ref);
+    let current = match current {
+        Some(FileRef { v1: Some(v), .. }) => v,
+        _ => {
+            return LiveStatus {
+                message_exists: message.is_some(),
+                document_matches: false,
+                file_reference_stale: false,
+                refreshed_ref: None,
+                error: None,
+            };
+        }
+    };
+
+    let document_matches = current.document_id == file_ref.document_id;
+    let file_reference_stale = document_matches && current.file_reference != file_ref.file_reference;
+
+    LiveStatus {
+        message_exists: true,
+        document_matches,
+        file_reference_stale,
+        refreshed_ref: if file_reference_stale { Some(FileRef { v1: Some(FileRefV1 { peer: file_ref.peer.clone(), copy_id: file_ref.copy_id, ..current }), ..Default::default() }.to_ref_string()) } else { None },
+        error: None,
+    }
+}
+
+/// `teleton inspect-ref <ref> [--live]`
+pub async fn run_cli(args: &[String]) {
+    let mut live = false;
+    let mut input = None;
+    for arg in args {
+        match arg.as_str() {
+            "--live" => live = true,
+            _ => input = Some(arg.clone()),
+        }
+    }
+
+    let input = match input {
+        Some(v) => v,
+        None => {
+            eprintln!("usage: teleton inspect-ref <ref> [--live]");
+            std::process::exit(2);
+        }
+    };
+
+    let res = if live {
+        let client = crate::teleauth::get_authorized_client().await;
+        inspect(&input, Some(&client)).await
+    } else {
+        inspect(&input, None).await
+    };
+
+    println!("{}", serde_json::to_string_pretty(&res).unwrap());
+}
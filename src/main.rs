@@ -1,14 +1,62 @@
-use axum::{body::Bytes, extract::{Path, Query}, http::HeaderMap, routing::{get, post}, Json};
+use std::sync::Arc;
+
+use axum::{body::{Body, Bytes}, extract::{DefaultBodyLimit, Multipart, Path, Query}, http::HeaderMap, routing::{delete, get, head, options, post, put}, Json};
 
 mod teleauth;
 mod handlers;
+mod compression;
+mod crypto;
+mod dedup;
+mod finalized;
+mod inspect;
+mod media;
+mod store;
+mod thumbnail;
+mod transfers;
+mod uploads;
+pub mod config;
 pub mod shared;
 
 pub mod proto;
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|x| x.as_str()) == Some("inspect-ref") {
+        inspect::run_cli(&args[2..]).await;
+        return;
+    }
+
     let client = teleauth::get_authorized_client().await;
+    let transfers = Arc::new(transfers::TransferPool::new(client.clone(), teleauth::connect_transfer_clients(config::get().transfer_connections).await));
+
+    let uploads = Arc::new(uploads::UploadRegistry::new());
+    let tus = Arc::new(handlers::upload::TusRegistry::new());
+    let fetch_jobs = Arc::new(handlers::upload::FetchJobRegistry::new());
+    let limits = Arc::new(handlers::upload::UploadLimitCache::new());
+    let dedup = Arc::new(dedup::DedupIndex::load(config::get().state_dir.join("dedup.json")));
+    let finalized = Arc::new(finalized::FinalizedUploads::load(config::get().state_dir.join("finalized.json")));
+
+    {
+        let uploads = uploads.clone();
+        let tus = tus.clone();
+        let fetch_jobs = fetch_jobs.clone();
+        tokio::spawn(async move {
+            let ttl = config::get().upload_idle_ttl;
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let expired = uploads.expire_idle(ttl) + tus.expire_idle(ttl);
+                if expired > 0 {
+                    println!("Forgot {} idle uploads", expired);
+                }
+                let expired = fetch_jobs.expire_idle(ttl);
+                if expired > 0 {
+                    println!("Forgot {} finished fetch jobs", expired);
+                }
+            }
+        });
+    }
 
     println!("Starting...");
 
@@ -16,31 +64,128 @@ async fn main() {
     let app = app.route("/", get(|| async { "Hello, world!" }));
     let app = {
         let client = client.clone();
+        let limits = limits.clone();
         app.route("/v1/upload/limit", get(|| async move {
-            handlers::upload::get_upload_limit(&client).await
+            handlers::upload::get_upload_limit(&client, &limits).await
         }))
     };
     let app = {
+        let client = client.clone();
+        let uploads = uploads.clone();
+        let limits = limits.clone();
         app.route("/v1/upload/start", post(|Query(query): Query<handlers::upload::StartUploadQueryParams>| async move {
-            handlers::upload::start_upload(query).await
+            handlers::upload::start_upload(&client, &uploads, &limits, query).await
         }))
     };
     let app = {
-        let client = client.clone();
+        let transfers = transfers.clone();
+        let uploads = uploads.clone();
         app.route("/v1/upload/chunk", post(|Query(query): Query<handlers::upload::UploadChunkQueryParams>, body: Bytes| async move {
-            handlers::upload::upload_chunk(&client, query, Vec::from(body)).await
+            handlers::upload::upload_chunk(&transfers, &uploads, query, Vec::from(body)).await
         }))
     };
+    let app = {
+        let uploads = uploads.clone();
+        app.route("/v1/upload/status", get(|Query(query): Query<handlers::upload::UploadStatusQueryParams>| async move {
+            handlers::upload::get_upload_status(&uploads, query).await
+        }))
+    };
+    let app = {
+        let client = client.clone();
+        let transfers = transfers.clone();
+        let uploads = uploads.clone();
+        let dedup = dedup.clone();
+        let finalized = finalized.clone();
+        let limits = limits.clone();
+        app.route("/v1/upload/form", post(|Query(query): Query<handlers::upload::UploadFormQueryParams>, multipart: Multipart| async move {
+            handlers::upload::upload_form(&client, &transfers, &uploads, &dedup, &finalized, &limits, query, multipart).await
+        }).layer(DefaultBodyLimit::disable()))
+    };
     let app = {
         let client = client.clone();
+        let uploads = uploads.clone();
+        let dedup = dedup.clone();
+        let finalized = finalized.clone();
         app.route("/v1/upload/finalize", post(|Query(query): Query<handlers::upload::UploadFinalizeQueryParams>, Json(body): Json<handlers::upload::UploadFinalizeBody>| async move {
-            handlers::upload::upload_finalize(query, body, &client).await
+            handlers::upload::upload_finalize(query, body, &client, &uploads, &dedup, &finalized).await
         }))
     };
     let app = {
         let client = client.clone();
-        app.route("/v1/files/:file_ref/chunks/:offset", get(|Path((file_ref, offset)): Path<(String, usize)>, headers: HeaderMap| async move {
-            handlers::files::chunk::get_chunk(&client, file_ref, offset).await
+        let transfers = transfers.clone();
+        let uploads = uploads.clone();
+        let dedup = dedup.clone();
+        let finalized = finalized.clone();
+        let limits = limits.clone();
+        let tus = tus.clone();
+        app.route("/v1/tus", options(handlers::upload::tus_options).post(|headers: HeaderMap| async move {
+            handlers::upload::tus_create(&client, &transfers, &uploads, &dedup, &finalized, &limits, &tus, &headers).await
+        }))
+    };
+    let app = {
+        let tus_for_head = tus.clone();
+        let tus_for_patch = tus.clone();
+        let tus_for_delete = tus.clone();
+        app.route("/v1/tus/:id", options(handlers::upload::tus_options).head(|Path(id): Path<String>, headers: HeaderMap| async move {
+            handlers::upload::tus_head(&tus_for_head, &headers, id).await
+        }).patch(|Path(id): Path<String>, headers: HeaderMap, body: Body| async move {
+            handlers::upload::tus_patch(&tus_for_patch, &headers, id, body).await
+        }).delete(|Path(id): Path<String>, headers: HeaderMap| async move {
+            handlers::upload::tus_delete(&tus_for_delete, &headers, id).await
+        }))
+    };
+    let app = {
+        let client_for_put = client.clone();
+        let client_for_list = client.clone();
+        let transfers = transfers.clone();
+        let uploads = uploads.clone();
+        let dedup = dedup.clone();
+        let finalized = finalized.clone();
+        let limits = limits.clone();
+        app.route("/v1/files", put(|Query(query): Query<handlers::upload::PutFileQueryParams>, headers: HeaderMap, body: Body| async move {
+            handlers::upload::put_file(&client_for_put, &transfers, &uploads, &dedup, &finalized, &limits, query, &headers, body).await
+        }).get(|Query(query): Query<handlers::files::list::ListFilesQueryParams>| async move {
+            handlers::files::list::list_files(&client_for_list, query).await
+        }))
+    };
+    let app = {
+        let client = client.clone();
+        let transfers = transfers.clone();
+        let uploads = uploads.clone();
+        let dedup = dedup.clone();
+        let finalized = finalized.clone();
+        let limits = limits.clone();
+        let fetch_jobs = fetch_jobs.clone();
+        app.route("/v1/upload/from-url", post(|Json(body): Json<handlers::upload::UploadFromUrlBody>| async move {
+            handlers::upload::upload_from_url(&client, &transfers, &uploads, &dedup, &finalized, &limits, &fetch_jobs, body).await
+        }))
+    };
+    let app = {
+        let fetch_jobs = fetch_jobs.clone();
+        app.route("/v1/upload/from-url/:job_id", get(|Path(job_id): Path<String>| async move {
+            handlers::upload::get_fetch_job(&fetch_jobs, job_id).await
+        }))
+    };
+    let app = {
+        let uploads = uploads.clone();
+        let finalized = finalized.clone();
+        app.route("/v1/upload/events", get(|Query(query): Query<handlers::upload::UploadEventsQueryParams>| async move {
+            handlers::upload::upload_events(&uploads, &finalized, query).await
+        }))
+    };
+    let app = {
+        let uploads = uploads.clone();
+        app.route("/v1/upload", delete(|Query(query): Query<handlers::upload::AbortUploadQueryParams>| async move {
+            handlers::upload::abort_upload(&uploads, query).await
+        }))
+    };
+    let app = {
+        let client = client.clone();
+        let transfers = transfers.clone();
+        app.route("/v1/files/:file_ref/chunks/:offset", get(|Path((file_ref, offset)): Path<(String, usize)>, Query(query): Query<handlers::files::chunk::GetChunkQueryParams>, headers: HeaderMap| async move {
+            handlers::files::chunk::get_chunk(&client, &transfers, file_ref, offset, query, &headers).await
+        }).head(|Path((file_ref, offset)): Path<(String, usize)>, Query(query): Query<handlers::files::chunk::GetChunkQueryParams>, headers: HeaderMap| async move {
+            handlers::files::chunk::head_chunk(file_ref, offset, query, &headers).await
         }))
     };
     let app = {
@@ -49,6 +194,60 @@ async fn main() {
             handlers::files::meta::get_file_meta(&client, file_ref).await
         }))
     };
+    let app = {
+        let client_for_claim = client.clone();
+        let dedup_for_head = dedup.clone();
+        let dedup_for_claim = dedup.clone();
+        app.route("/v1/files/by-hash/:sha256", head(|Path(sha256): Path<String>| async move {
+            handlers::files::by_hash::head_by_hash(&dedup_for_head, sha256).await
+        }).post(|Path(sha256): Path<String>| async move {
+            handlers::files::by_hash::claim_by_hash(&client_for_claim, &dedup_for_claim, sha256).await
+        }))
+    };
+    let app = {
+        let client_for_download = client.clone();
+        let client_for_head = client.clone();
+        let client_for_delete = client.clone();
+        let transfers = transfers.clone();
+        let dedup = dedup.clone();
+        app.route("/v1/files/:file_ref", get(|Path(file_ref): Path<String>, headers: HeaderMap| async move {
+            handlers::files::download::download_file(&client_for_download, &transfers, file_ref, &headers).await
+        }).head(|Path(file_ref): Path<String>, headers: HeaderMap| async move {
+            handlers::files::download::head_file(&client_for_head, file_ref, &headers).await
+        }).delete(|Path(file_ref): Path<String>| async move {
+            handlers::files::delete::delete_file(&client_for_delete, &dedup, file_ref).await
+        }))
+    };
+    let app = {
+        let client = client.clone();
+        app.route("/v1/files/:file_ref/copy", post(|Path(file_ref): Path<String>, Json(body): Json<handlers::files::copy::CopyFileBody>| async move {
+            handlers::files::copy::copy_file(&client, file_ref, body).await
+        }))
+    };
+    let app = {
+        let client = client.clone();
+        app.route("/v1/files/:file_ref/thumbnail", get(|Path(file_ref): Path<String>| async move {
+            handlers::files::thumbnail::get_thumbnail(&client, file_ref).await
+        }))
+    };
+    let app = {
+        let client = client.clone();
+        app.route("/v1/debug/refs/:ref", get(|Path(input): Path<String>, Query(query): Query<handlers::debug::InspectRefQueryParams>, headers: HeaderMap| async move {
+            handlers::debug::inspect_ref(&client, &headers, input, query).await
+        }))
+    };
+    let app = {
+        let uploads = uploads.clone();
+        app.route("/v1/admin/uploads", get(|headers: HeaderMap| async move {
+            handlers::admin::list_uploads(&uploads, &headers).await
+        }))
+    };
+    let app = {
+        let transfers = transfers.clone();
+        app.route("/v1/admin/transfers", get(|headers: HeaderMap| async move {
+            handlers::admin::list_transfers(&transfers, &headers).await
+        }))
+    };
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.expect("Failed to bind");
     axum::serve(listener, app).await.unwrap();
@@ -0,0 +1,559 @@
+//! Reads just enough of MP4, Matroska and MP3 headers to fill upstream's video/audio attributes.
+
+/// What could be read from the container. Missing values are left as `None`.
+#[derive(Default, Debug)]
+pub struct MediaInfo {
+    /// in seconds
+    pub duration: Option<f64>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    /// true if the file can be played before it's fully downloaded (MP4 with `moov` before `mdat`)
+    pub supports_streaming: bool,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+}
+
+/// Probes the file from its beginning (`head`) and optionally its end (`tail`, with the offset where it starts).
+pub fn probe(head: &[u8], tail: Option<(u64, &[u8])>, file_size: u64) -> Option<MediaInfo> {
+    let file = PartialFile { head, tail, file_size };
+    if head.len() >= 8 && &head[4..8] == b"ftyp" {
+        return probe_mp4(&file);
+    }
+    if head.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        return probe_matroska(head);
+    }
+    probe_mp3(head, file_size)
+}
+
+/// Parts of a file which we have in memory.
+struct PartialFile<'a> {
+    head: &'a [u8],
+    tail: Option<(u64, &'a [u8])>,
+    file_size: u64,
+}
+
+impl<'a> PartialFile<'a> {
+    /// Returns the bytes from `offset` up to the end of whichever part contains it.
+    fn at(&self, offset: u64) -> Option<&'a [u8]> {
+        if offset < self.head.len() as u64 {
+            return Some(&self.head[offset as usize..]);
+        }
+        let (tail_offset, tail) = self.tail?;
+        if offset >= tail_offset && offset < tail_offset + tail.len() as u64 {
+            return Some(&tail[(offset - tail_offset) as usize..]);
+        }
+        None
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/// Parses an ISO BMFF box header, and returns `(type, header size, box size)`.
+/// The box size is `None` if the box extends to the end of the file.
+fn mp4_box_header(data: &[u8]) -> Option<([u8; 4], usize, Option<u64>)> {
+    let size = read_u32(data, 0)?;
+    let box_type: [u8; 4] = data.get(4..8)?.try_into().ok()?;
+    match size {
+        0 => Some((box_type, 8, None)),
+        1 => Some((box_type, 16, Some(read_u64(data, 8)?))),
+        size => Some((box_type, 8, Some(size as u64))),
+    }
+}
+
+fn probe_mp4(file: &PartialFile) -> Option<MediaInfo> {
+    let mut offset = 0u64;
+    let mut seen_mdat = false;
+    while offset < file.file_size {
+        let data = match file.at(offset) {
+            Some(v) => v,
+            None => {
+                println!("mp4: box at {} isn't available, giving up", offset);
+                return None;
+            }
+        };
+        let (box_type, header_size, size) = mp4_box_header(data)?;
+        match &box_type {
+            b"moov" => {
+                // moov may be truncated if it's larger than what we have, but mvhd/tkhd usually come first
+                let end = match size {
+                    Some(size) => usize::min(size as usize, data.len()),
+                    None => data.len(),
+                };
+                let mut info = parse_moov(data.get(header_size..end)?);
+                info.supports_streaming = !seen_mdat;
+                return Some(info);
+            },
+            b"mdat" => seen_mdat = true,
+            _ => {},
+        }
+        match size {
+            Some(size) if size >= header_size as u64 => offset = offset.saturating_add(size),
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Calls `f` with the type and body of each box in `data`, ignoring a truncated box at the end.
+fn each_mp4_box(data: &[u8], mut f: impl FnMut(&[u8; 4], &[u8])) {
+    let mut offset = 0;
+    while let Some((box_type, header_size, size)) = mp4_box_header(&data[offset..]) {
+        let end = match size {
+            Some(size) if size >= header_size as u64 => usize::min(offset.saturating_add(size as usize), data.len()),
+            Some(_) => return,
+            None => data.len(),
+        };
+        f(&box_type, &data[offset + header_size..end]);
+        offset = end;
+        if offset >= data.len() {
+            return;
+        }
+    }
+}
+
+fn parse_moov(moov: &[u8]) -> MediaInfo {
+    let mut info = MediaInfo::default();
+    each_mp4_box(moov, |box_type, body| match box_type {
+        b"mvhd" => {
+            // version 1 has 64-bit times and duration
+            let (timescale, duration) = match body.first() {
+                Some(1) => (read_u32(body, 20), read_u64(body, 24)),
+                _ => (read_u32(body, 12), read_u32(body, 16).map(|x| x as u64)),
+            };
+            if let (Some(timescale), Some(duration)) = (timescale, duration) {
+                if timescale > 0 {
+                    info.duration = Some(duration as f64 / timescale as f64);
+                }
+            }
+        },
+        b"trak" => each_mp4_box(body, |box_type, body| {
+            if box_type != b"tkhd" || info.width.is_some() {
+                return;
+            }
+            let offset = match body.first() {
+                Some(1) => 88,
+                _ => 76,
+            };
+            // 16.16 fixed point, zero for non-visual tracks
+            let width = read_u32(body, offset).map(|x| (x >> 16) as i32).unwrap_or(0);
+            let height = read_u32(body, offset + 4).map(|x| (x >> 16) as i32).unwrap_or(0);
+            if width > 0 && height > 0 {
+                info.width = Some(width);
+                info.height = Some(height);
+            }
+        }),
+        _ => {},
+    });
+    info
+}
+
+/// Reads an EBML variable length integer, and returns `(value, length)`. The marker bit is kept if `keep_marker`.
+fn read_vint(data: &[u8], keep_marker: bool) -> Option<(u64, usize)> {
+    let first = *data.first()?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 {
+        return None;
+    }
+    let mut value = if keep_marker { first as u64 } else { (first as u64) & (0xFF >> len) };
+    for b in data.get(1..len)? {
+        value = (value << 8) | *b as u64;
+    }
+    Some((value, len))
+}
+
+/// Calls `f` with the id and body of each element in `data`. Elements with unknown size get the rest of `data`.
+fn each_ebml_element<'a>(data: &'a [u8], mut f: impl FnMut(u64, &'a [u8]) -> bool) {
+    let mut offset = 0;
+    while offset < data.len() {
+        let (id, id_len) = match read_vint(&data[offset..], true) {
+            Some(v) => v,
+            None => return,
+        };
+        let (size, size_len) = match read_vint(&data[offset + id_len..], false) {
+            Some(v) => v,
+            None => return,
+        };
+        let start = offset + id_len + size_len;
+        // all ones means the size is unknown
+        let unknown = size == (1u64 << (7 * size_len)) - 1;
+        let end = if unknown { data.len() } else { usize::min(start.saturating_add(size as usize), data.len()) };
+        if start > end || !f(id, &data[start..end]) {
+            return;
+        }
+        offset = end;
+    }
+}
+
+fn read_ebml_uint(data: &[u8]) -> Option<u64> {
+    if data.is_empty() || data.len() > 8 {
+        return None;
+    }
+    Some(data.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64))
+}
+
+fn read_ebml_float(data: &[u8]) -> Option<f64> {
+    match data.len() {
+        4 => Some(f32::from_be_bytes(data.try_into().ok()?) as f64),
+        8 => Some(f64::from_be_bytes(data.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+const EBML_SEGMENT: u64 = 0x18538067;
+const EBML_INFO: u64 = 0x1549A966;
+const EBML_TIMESTAMP_SCALE: u64 = 0x2AD7B1;
+const EBML_DURATION: u64 = 0x4489;
+const EBML_TRACKS: u64 = 0x1654AE6B;
+const EBML_TRACK_ENTRY: u64 = 0xAE;
+const EBML_VIDEO: u64 = 0xE0;
+const EBML_PIXEL_WIDTH: u64 = 0xB0;
+const EBML_PIXEL_HEIGHT: u64 = 0xBA;
+const EBML_CLUSTER: u64 = 0x1F43B675;
+
+fn probe_matroska(head: &[u8]) -> Option<MediaInfo> {
+    let mut segment = None;
+    each_ebml_element(head, |id, body| {
+        if id == EBML_SEGMENT {
+            segment = Some(body);
+            return false;
+        }
+        true
+    });
+
+    let mut info = MediaInfo::default();
+    let mut timestamp_scale = 1_000_000u64;
+    let mut duration = None;
+    each_ebml_element(segment?, |id, body| {
+        match id {
+            EBML_INFO => each_ebml_element(body, |id, body| {
+                match id {
+                    EBML_TIMESTAMP_SCALE => timestamp_scale = read_ebml_uint(body).unwrap_or(timestamp_scale),
+                    EBML_DURATION => duration = read_ebml_float(body),
+                    _ => {},
+                }
+                true
+            }),
+            EBML_TRACKS => each_ebml_element(body, |id, body| {
+                if id == EBML_TRACK_ENTRY && info.width.is_none() {
+                    each_ebml_element(body, |id, body| {
+                        if id == EBML_VIDEO {
+                            each_ebml_element(body, |id, body| {
+                                match id {
+                                    EBML_PIXEL_WIDTH => info.width = read_ebml_uint(body).map(|x| x as i32),
+                                    EBML_PIXEL_HEIGHT => info.height = read_ebml_uint(body).map(|x| x as i32),
+                                    _ => {},
+                                }
+                                true
+                            });
+                        }
+                        true
+                    });
+                }
+                true
+            }),
+            // metadata is before clusters in files written by common muxers
+            EBML_CLUSTER => return false,
+            _ => {},
+        }
+        true
+    });
+
+    // Duration is in units of TimestampScale nanoseconds
+    info.duration = duration.map(|x| x * timestamp_scale as f64 / 1_000_000_000.0);
+    Some(info)
+}
+
+const MP3_BITRATES_V1: [u32; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+const MP3_BITRATES_V2: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+fn probe_mp3(head: &[u8], file_size: u64) -> Option<MediaInfo> {
+    let mut info = MediaInfo::default();
+
+    let mut offset = 0;
+    if head.starts_with(b"ID3") && head.len() >= 10 {
+        let size = syncsafe(&head[6..10]) as usize;
+        let footer = if head[5] & 0x10 != 0 { 10 } else { 0 };
+        let tag = &head[10..usize::min(10 + size, head.len())];
+        parse_id3v2(head[3], tag, &mut info);
+        offset = 10 + size + footer;
+    }
+
+    // find the first MPEG audio layer III frame, which may be past the head if the tag is large (e.g. cover art)
+    let data = match head.get(offset..) {
+        Some(v) => v,
+        None if offset > 0 => return Some(info),
+        None => return None,
+    };
+    let start = (0..data.len().saturating_sub(4)).find(|i| data[*i] == 0xFF && data[*i + 1] & 0xE6 == 0xE2)?;
+    let frame = &data[start..];
+    let header = read_u32(frame, 0)?;
+
+    let version = (header >> 19) & 0b11;
+    let bitrate_index = ((header >> 12) & 0b1111) as usize;
+    let sample_rate_index = ((header >> 10) & 0b11) as usize;
+    let mono = (header >> 6) & 0b11 == 0b11;
+    if version == 0b01 || bitrate_index == 0b1111 || sample_rate_index == 0b11 {
+        return None;
+    }
+
+    let mpeg1 = version == 0b11;
+    let sample_rate = match version {
+        0b11 => [44100, 48000, 32000][sample_rate_index],
+        0b10 => [22050, 24000, 16000][sample_rate_index],
+        _ => [11025, 12000, 8000][sample_rate_index],
+    } as f64;
+    let samples_per_frame = if mpeg1 { 1152.0 } else { 576.0 };
+    let bitrate = if mpeg1 { MP3_BITRATES_V1[bitrate_index] } else { MP3_BITRATES_V2[bitrate_index] };
+
+    // VBR files have the frame count in a Xing/Info or VBRI header in the first frame
+    let side_info = match (mpeg1, mono) {
+        (true, false) => 32,
+        (true, true) | (false, false) => 17,
+        (false, true) => 9,
+    };
+    let xing = frame.get(4 + side_info..);
+    let frames = match xing {
+        Some(x) if x.starts_with(b"Xing") || x.starts_with(b"Info") => {
+            read_u32(x, 4).filter(|flags| flags & 1 != 0).and_then(|_| read_u32(x, 8))
+        },
+        _ => match frame.get(36..) {
+            Some(x) if x.starts_with(b"VBRI") => read_u32(x, 14),
+            _ => None,
+        },
+    };
+
+    info.duration = match frames {
+        Some(frames) => Some(frames as f64 * samples_per_frame / sample_rate),
+        None if bitrate > 0 => {
+            let audio_size = file_size.saturating_sub((offset + start) as u64);
+            Some(audio_size as f64 * 8.0 / (bitrate as f64 * 1000.0))
+        },
+        None => None,
+    };
+    Some(info)
+}
+
+fn syncsafe(data: &[u8]) -> u32 {
+    data.iter().fold(0u32, |acc, b| (acc << 7) | (*b & 0x7F) as u32)
+}
+
+/// Reads title (TIT2) and artist (TPE1) from an ID3v2.3/2.4 tag.
+fn parse_id3v2(major_version: u8, tag: &[u8], info: &mut MediaInfo) {
+    if major_version != 3 && major_version != 4 {
+        return;
+    }
+    let mut offset = 0;
+    while let Some(frame_header) = tag.get(offset..offset + 10) {
+        if frame_header[0] == 0 {
+            // padding
+            return;
+        }
+        let size = match major_version {
+            4 => syncsafe(&frame_header[4..8]),
+            _ => read_u32(frame_header, 4).unwrap_or(0),
+        } as usize;
+        let body = match tag.get(offset + 10..(offset + 10).saturating_add(size)) {
+            Some(v) => v,
+            None => return,
+        };
+        match &frame_header[0..4] {
+            b"TIT2" => info.title = decode_id3_text(body),
+            b"TPE1" => info.performer = decode_id3_text(body),
+            _ => {},
+        }
+        offset += 10 + size;
+    }
+}
+
+fn decode_id3_text(body: &[u8]) -> Option<String> {
+    let (encoding, text) = body.split_first()?;
+    let text = match encoding {
+        // ISO-8859-1
+        0 => text.iter().map(|b| *b as char).collect(),
+        1 | 2 => {
+            let (big_endian, text) = match text {
+                [0xFF, 0xFE, rest @ ..] => (false, rest),
+                [0xFE, 0xFF, rest @ ..] => (true, rest),
+                _ => (*encoding == 2, text),
+            };
+            let units: Vec<u16> = text.chunks_exact(2).map(|x| {
+                if big_endian { u16::from_be_bytes([x[0], x[1]]) } else { u16::from_le_bytes([x[0], x[1]]) }
+            }).collect();
+            String::from_utf16_lossy(&units)
+        },
+        3 => String::from_utf8_lossy(text).to_string(),
+        _ => return None,
+    };
+    let text = text.trim_end_matches('\0').to_string();
+    if text.is_empty() { None } else { Some(text) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(box_type: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn mvhd(timescale: u32, duration: u32) -> Vec<u8> {
+        let mut body = vec![0u8; 100];
+        body[12..16].copy_from_slice(&timescale.to_be_bytes());
+        body[16..20].copy_from_slice(&duration.to_be_bytes());
+        mp4_box(b"mvhd", &body)
+    }
+
+    fn trak(width: u32, height: u32) -> Vec<u8> {
+        let mut body = vec![0u8; 84];
+        body[76..80].copy_from_slice(&(width << 16).to_be_bytes());
+        body[80..84].copy_from_slice(&(height << 16).to_be_bytes());
+        mp4_box(b"trak", &mp4_box(b"tkhd", &body))
+    }
+
+    fn moov() -> Vec<u8> {
+        mp4_box(b"moov", &[mvhd(1000, 5000), trak(640, 480)].concat())
+    }
+
+    #[test]
+    fn mp4_moov_before_mdat() {
+        let file = [mp4_box(b"ftyp", b"isom"), moov(), mp4_box(b"mdat", &[0; 16])].concat();
+        let info = probe(&file, None, file.len() as u64).unwrap();
+        assert_eq!(info.duration, Some(5.0));
+        assert_eq!((info.width, info.height), (Some(640), Some(480)));
+        assert!(info.supports_streaming);
+    }
+
+    #[test]
+    fn mp4_moov_in_tail() {
+        let head = [mp4_box(b"ftyp", b"isom"), mp4_box(b"mdat", &[0; 1000])].concat();
+        let moov = moov();
+        let file_size = (head.len() + moov.len()) as u64;
+        let info = probe(&head[..100], Some((head.len() as u64, &moov)), file_size).unwrap();
+        assert_eq!(info.duration, Some(5.0));
+        assert!(!info.supports_streaming);
+    }
+
+    #[test]
+    fn mp4_truncated_moov() {
+        let moov = moov();
+        let file = [mp4_box(b"ftyp", b"isom"), moov[..moov.len() - 20].to_vec()].concat();
+        let info = probe(&file, None, 1_000_000).unwrap();
+        assert_eq!(info.duration, Some(5.0));
+    }
+
+    #[test]
+    fn mp4_oversized_boxes() {
+        // 64-bit size which overflows when added to the offset
+        let mut huge = 1u32.to_be_bytes().to_vec();
+        huge.extend_from_slice(b"free");
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+
+        let file = [mp4_box(b"ftyp", b"isom"), huge.clone()].concat();
+        assert!(probe(&file, None, u64::MAX).is_none());
+
+        let file = [mp4_box(b"ftyp", b"isom"), mp4_box(b"moov", &[mvhd(1000, 5000), huge].concat())].concat();
+        let info = probe(&file, None, file.len() as u64).unwrap();
+        assert_eq!(info.duration, Some(5.0));
+    }
+
+    #[test]
+    fn mp4_box_smaller_than_header() {
+        let mut file = mp4_box(b"ftyp", b"isom");
+        file.extend_from_slice(&4u32.to_be_bytes());
+        file.extend_from_slice(b"free");
+        assert!(probe(&file, None, 1_000_000).is_none());
+    }
+
+    fn ebml(id: &[u8], body: &[u8]) -> Vec<u8> {
+        [id, &[0x80 | body.len() as u8], body].concat()
+    }
+
+    #[test]
+    fn matroska() {
+        let info = ebml(&[0x15, 0x49, 0xA9, 0x66], &[
+            ebml(&[0x2A, 0xD7, 0xB1], &[0x0F, 0x42, 0x40]),
+            ebml(&[0x44, 0x89], &2000f32.to_be_bytes()),
+        ].concat());
+        let tracks = ebml(&[0x16, 0x54, 0xAE, 0x6B], &ebml(&[0xAE], &ebml(&[0xE0], &[
+            ebml(&[0xB0], &[0x07, 0x80]),
+            ebml(&[0xBA], &[0x04, 0x38]),
+        ].concat())));
+        // unknown size
+        let segment = [&[0x18, 0x53, 0x80, 0x67, 0xFF][..], &info, &tracks].concat();
+        let file = [ebml(&[0x1A, 0x45, 0xDF, 0xA3], &[]), segment].concat();
+
+        let info = probe(&file, None, file.len() as u64).unwrap();
+        assert_eq!(info.duration, Some(2.0));
+        assert_eq!((info.width, info.height), (Some(1920), Some(1080)));
+    }
+
+    #[test]
+    fn matroska_truncated() {
+        // the segment claims more than there is
+        let file = [ebml(&[0x1A, 0x45, 0xDF, 0xA3], &[]), vec![0x18, 0x53, 0x80, 0x67, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE, 0x15]].concat();
+        assert!(probe(&file, None, file.len() as u64).is_some());
+    }
+
+    /// MPEG-1 layer III, 128 kbps, 44.1 kHz, joint stereo
+    const MP3_FRAME_HEADER: [u8; 4] = [0xFF, 0xFB, 0x90, 0x64];
+
+    #[test]
+    fn mp3_cbr() {
+        let mut head = MP3_FRAME_HEADER.to_vec();
+        head.resize(400, 0);
+        let info = probe(&head, None, 16000).unwrap();
+        assert_eq!(info.duration, Some(1.0));
+    }
+
+    fn id3_tag(size: u32, frames: &[u8]) -> Vec<u8> {
+        let syncsafe = [(size >> 21) & 0x7F, (size >> 14) & 0x7F, (size >> 7) & 0x7F, size & 0x7F].map(|x| x as u8);
+        [&b"ID3\x03\x00\x00"[..], &syncsafe, frames].concat()
+    }
+
+    fn id3_text_frame(id: &[u8; 4], text: &str) -> Vec<u8> {
+        let body = [&[3u8][..], text.as_bytes()].concat();
+        [&id[..], &(body.len() as u32).to_be_bytes(), &[0, 0], &body].concat()
+    }
+
+    #[test]
+    fn mp3_id3_tags() {
+        let frames = [id3_text_frame(b"TIT2", "Title"), id3_text_frame(b"TPE1", "Artist")].concat();
+        let head = [id3_tag(frames.len() as u32, &frames), MP3_FRAME_HEADER.to_vec(), vec![0; 400]].concat();
+        let info = probe(&head, None, 16000 + 10 + frames.len() as u64).unwrap();
+        assert_eq!(info.title.as_deref(), Some("Title"));
+        assert_eq!(info.performer.as_deref(), Some("Artist"));
+        assert_eq!(info.duration, Some(1.0));
+    }
+
+    #[test]
+    fn mp3_id3_tag_larger_than_head() {
+        let frames = id3_text_frame(b"TIT2", "Title");
+        let head = id3_tag(1_000_000, &frames);
+        let info = probe(&head, None, 2_000_000).unwrap();
+        assert_eq!(info.title.as_deref(), Some("Title"));
+        assert_eq!(info.duration, None);
+    }
+
+    #[test]
+    fn id3_frame_larger_than_tag() {
+        let frame = [&b"TIT2"[..], &u32::MAX.to_be_bytes(), &[0, 0, 3], b"Title"].concat();
+        let head = [id3_tag(frame.len() as u32, &frame), MP3_FRAME_HEADER.to_vec(), vec![0; 400]].concat();
+        let info = probe(&head, None, 16000).unwrap();
+        assert_eq!(info.title, None);
+    }
+
+    #[test]
+    fn unknown_format() {
+        assert!(probe(b"not a media file", None, 16).is_none());
+        assert!(probe(&[], None, 0).is_none());
+    }
+}
@@ -1,13 +1,168 @@
 use base64::Engine;
+use grammers_client::grammers_tl_types as tl;
 use prost::Message;
 
-use crate::shared::CHUNK_SIZE;
+use crate::{config, crypto::{self, TAG_SIZE}, shared::{is_valid_download_chunk_size, BIG_UPLOAD_THRESHOLD, MAX_PART_SIZE, MIN_DOWNLOAD_CHUNK_SIZE}};
 
 include!(concat!(env!("OUT_DIR"), "/_.rs"));
 
+/// Part sizes were fixed to 512 KiB before they were recorded.
+fn part_size_or_default(part_size: i32) -> usize {
+    match part_size {
+        0 => MAX_PART_SIZE,
+        v => v as usize,
+    }
+}
+
+impl FileEncryption {
+    /// Stored size of a part, which is encrypted separately.
+    pub fn part_size(&self) -> usize {
+        part_size_or_default(self.part_size)
+    }
+
+    /// Size of the content in a part, without the tag.
+    pub fn content_part_size(&self) -> usize {
+        self.part_size() - TAG_SIZE
+    }
+}
+
+impl PeerRef {
+    /// `None` is Saved Messages, which most refs are in.
+    pub fn input_peer(peer: Option<&PeerRef>) -> tl::enums::InputPeer {
+        let peer = match peer {
+            Some(v) => v,
+            None => return tl::enums::InputPeer::PeerSelf,
+        };
+        match PeerKind::try_from(peer.kind) {
+            Ok(PeerKind::User) => tl::enums::InputPeer::User(tl::types::InputPeerUser { user_id: peer.id, access_hash: peer.access_hash }),
+            Ok(PeerKind::Chat) => tl::enums::InputPeer::Chat(tl::types::InputPeerChat { chat_id: peer.id }),
+            Ok(PeerKind::Channel) => tl::enums::InputPeer::Channel(tl::types::InputPeerChannel { channel_id: peer.id, access_hash: peer.access_hash }),
+            Ok(PeerKind::Self_) | Err(_) => tl::enums::InputPeer::PeerSelf,
+        }
+    }
+
+    /// Channels have their own message IDs, so they need the `channels.*` methods.
+    pub fn input_channel(peer: Option<&PeerRef>) -> Option<tl::enums::InputChannel> {
+        let peer = peer?;
+        match PeerKind::try_from(peer.kind) {
+            Ok(PeerKind::Channel) => Some(tl::enums::InputChannel::Channel(tl::types::InputChannel { channel_id: peer.id, access_hash: peer.access_hash })),
+            _ => None,
+        }
+    }
+}
+
 impl FileRef {
+    /// Refs which have a file key are sealed with the server key.
     pub fn to_ref_string(&self) -> String {
-        base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(self.encode_to_vec())
+        let bytes = match self.encryption() {
+            Some(_) if self.sealed.is_empty() => {
+                // encryption is only enabled when the server key exists
+                let sealed = crypto::seal(&self.encode_to_vec()).expect("server key is needed to seal refs");
+                FileRef { sealed, ..Default::default() }.encode_to_vec()
+            },
+            _ => self.encode_to_vec(),
+        };
+        base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    pub fn encryption(&self) -> Option<&FileEncryption> {
+        match (&self.v1, &self.composite_v1) {
+            (Some(v1), _) => v1.encryption.as_ref(),
+            (None, Some(composite)) => composite.encryption.as_ref(),
+            (None, None) => None,
+        }
+    }
+
+    /// Part size which the chunk API uses for this file by default.
+    pub fn chunk_size(&self) -> usize {
+        if let Some(size) = self.fixed_chunk_size() {
+            return size;
+        }
+        // chunks of composite files should not cross segments
+        let segment_size = self.composite_v1.as_ref().map(|x| x.segment_size as usize).unwrap_or(0);
+        let mut size = config::get().download_chunk_size;
+        while segment_size % size != 0 && size > MIN_DOWNLOAD_CHUNK_SIZE {
+            size /= 2;
+        }
+        size
+    }
+
+    /// Encrypted and compressed files can only be read by their parts or frames.
+    fn fixed_chunk_size(&self) -> Option<usize> {
+        if let Some(compression) = self.v1.as_ref().and_then(|x| x.compression.as_ref()) {
+            return Some(compression.frame_size as usize);
+        }
+        self.encryption().map(|x| x.content_part_size())
+    }
+
+    /// Whether the chunk API can read this file by chunks of `size`.
+    pub fn accepts_chunk_size(&self, size: usize) -> bool {
+        if let Some(fixed) = self.fixed_chunk_size() {
+            return size == fixed;
+        }
+        let segment_size = self.composite_v1.as_ref().map(|x| x.segment_size as usize).unwrap_or(0);
+        is_valid_download_chunk_size(size) && segment_size % size == 0
+    }
+
+    /// Size of the original content.
+    pub fn content_size(&self) -> i64 {
+        match (&self.v1, &self.composite_v1) {
+            (Some(v1), _) => v1.content_size(),
+            (None, Some(composite)) => composite.file_size,
+            (None, None) => 0,
+        }
+    }
+
+    /// Returns the messages which the file is stored in.
+    pub fn message_ids(&self) -> Vec<i32> {
+        match (&self.v1, &self.composite_v1) {
+            (Some(v1), _) => vec![v1.message_id],
+            (None, Some(composite)) => composite.segments.iter().map(|x| x.message_id).chain([composite.manifest_message_id]).collect(),
+            (None, None) => vec![],
+        }
+    }
+
+    /// The chat which the file's messages are in, or `None` for Saved Messages.
+    pub fn peer(&self) -> Option<&PeerRef> {
+        match (&self.v1, &self.composite_v1) {
+            (Some(v1), _) => v1.peer.as_ref(),
+            (None, Some(composite)) => composite.peer.as_ref(),
+            (None, None) => None,
+        }
+    }
+
+    pub fn set_peer(&mut self, peer: Option<PeerRef>) {
+        if let Some(v1) = &mut self.v1 {
+            v1.peer = peer;
+        } else if let Some(composite) = &mut self.composite_v1 {
+            composite.peer = peer;
+        }
+    }
+
+    /// Returns 0 unless the ref is one of the logical copies which share the stored file.
+    pub fn copy_id(&self) -> u64 {
+        match (&self.v1, &self.composite_v1) {
+            (Some(v1), _) => v1.copy_id,
+            (None, Some(composite)) => composite.copy_id,
+            (None, None) => 0,
+        }
+    }
+
+    pub fn set_copy_id(&mut self, copy_id: u64) {
+        if let Some(v1) = &mut self.v1 {
+            v1.copy_id = copy_id;
+        } else if let Some(composite) = &mut self.composite_v1 {
+            composite.copy_id = copy_id;
+        }
+    }
+
+    /// Returns the SHA-256 computed at upload, or an empty slice if it's unknown.
+    pub fn sha256(&self) -> &[u8] {
+        match (&self.v1, &self.composite_v1) {
+            (Some(v1), _) => &v1.sha256,
+            (None, Some(composite)) => &composite.sha256,
+            (None, None) => &[],
+        }
     }
 
     pub fn from_ref_string(input: String) -> Option<FileRef> {
@@ -16,11 +171,54 @@ impl FileRef {
             Err(_) => return None,
             Ok(v) => v,
         };
-        let decoded = FileRef::decode(&decoded[..]);
-        match decoded {
-            Ok(v) => Some(v),
-            Err(_) => None,
+        let decoded = match FileRef::decode(&decoded[..]) {
+            Ok(v) => v,
+            Err(_) => return None,
+        };
+        if decoded.sealed.is_empty() {
+            return Some(decoded);
         }
+        FileRef::decode(&crypto::unseal(&decoded.sealed)?[..]).ok()
+    }
+}
+
+impl FileRefV1 {
+    /// `file_size` is the stored size, which includes tags of every part if encrypted.
+    pub fn content_size(&self) -> i64 {
+        if let Some(compression) = &self.compression {
+            return compression.original_size;
+        }
+        match &self.encryption {
+            Some(encryption) => {
+                let part_size = encryption.part_size() as i64;
+                let parts = (self.file_size + part_size - 1) / part_size;
+                self.file_size - parts * TAG_SIZE as i64
+            },
+            None => self.file_size,
+        }
+    }
+
+    pub fn input_location(&self) -> tl::enums::InputFileLocation {
+        if self.photo_size_type.is_empty() {
+            tl::enums::InputFileLocation::InputDocumentFileLocation(tl::types::InputDocumentFileLocation {
+                id: self.document_id, access_hash: self.access_hash, file_reference: self.file_reference.clone(), thumb_size: "".to_string()
+            })
+        } else {
+            tl::enums::InputFileLocation::InputPhotoFileLocation(tl::types::InputPhotoFileLocation {
+                id: self.document_id, access_hash: self.access_hash, file_reference: self.file_reference.clone(), thumb_size: self.photo_size_type.clone()
+            })
+        }
+    }
+}
+
+impl CompositeFileRefV1 {
+    /// Returns the segment which has `offset` (with the peer and the copy id of the composite file), and the offset in it.
+    pub fn locate(&self, offset: u64) -> Option<(FileRefV1, u64)> {
+        if self.segment_size <= 0 || offset >= self.file_size as u64 {
+            return None;
+        }
+        let segment = self.segments.get((offset / self.segment_size as u64) as usize)?;
+        Some((FileRefV1 { peer: self.peer.clone(), copy_id: self.copy_id, ..segment.clone() }, offset % self.segment_size as u64))
     }
 }
 
@@ -31,24 +229,224 @@ impl UploadToken {
             Err(_) => return None,
             Ok(v) => v,
         };
-        let decoded = UploadToken::decode(&decoded[..]);
-        match decoded {
-            Ok(v) => Some(v),
-            Err(_) => None
+        let decoded = match UploadToken::decode(&decoded[..]) {
+            Ok(v) => v,
+            Err(_) => return None,
+        };
+        if decoded.sealed.is_empty() {
+            return Some(decoded);
         }
+        UploadToken::decode(&crypto::unseal(&decoded.sealed)?[..]).ok()
     }
 
+    /// Tokens which have a file key are sealed with the server key.
     pub fn to_api_string(&self) -> String {
-        base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(self.encode_to_vec())
+        let bytes = match self.v1.as_ref().and_then(|x| x.encryption.as_ref()) {
+            Some(_) if self.sealed.is_empty() => {
+                let sealed = crypto::seal(&self.encode_to_vec()).expect("server key is needed to seal tokens");
+                UploadToken { sealed, ..Default::default() }.encode_to_vec()
+            },
+            _ => self.encode_to_vec(),
+        };
+        base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(bytes)
     }
 }
 
 impl UploadTokenV1 {
     pub fn should_use_big_upload(&self) -> bool {
-        self.file_size >= 10 * 1024 * 1024
+        self.stored_size() >= BIG_UPLOAD_THRESHOLD
+    }
+
+    /// Size of a part in upstream.
+    pub fn part_size(&self) -> usize {
+        part_size_or_default(self.part_size)
+    }
+
+    /// Records the part size, also in the encryption settings since the stored file is decrypted by parts.
+    pub fn set_part_size(&mut self, part_size: usize) {
+        self.part_size = part_size as i32;
+        if let Some(encryption) = &mut self.encryption {
+            encryption.part_size = part_size as i32;
+        }
+    }
+
+    /// Part size of the content, which clients split the file by. Encrypted parts are stored with a tag, so their content is smaller.
+    pub fn chunk_size(&self) -> usize {
+        match &self.encryption {
+            Some(encryption) => encryption.content_part_size(),
+            None => self.part_size(),
+        }
+    }
+
+    /// Size in upstream, which includes tags of every part if encrypted.
+    pub fn stored_size(&self) -> i64 {
+        match self.encryption {
+            Some(_) => self.file_size + self.total_parts() as i64 * TAG_SIZE as i64,
+            None => self.file_size,
+        }
     }
 
     pub fn total_parts(&self) -> i32 {
-        ((self.file_size + (CHUNK_SIZE as i64) - 1) / (CHUNK_SIZE as i64)) as i32
+        let chunk_size = self.chunk_size() as i64;
+        ((self.file_size + chunk_size - 1) / chunk_size) as i32
+    }
+
+    /// Number of parts which the content is hashed and probed by. They are frames if compressed.
+    pub fn content_parts(&self) -> i32 {
+        match &self.compression {
+            Some(compression) => ((compression.original_size + compression.frame_size - 1) / compression.frame_size) as i32,
+            None => self.total_parts(),
+        }
+    }
+
+    pub fn is_composite(&self) -> bool {
+        self.segment_size > 0
+    }
+
+    /// Returns the uploads which the file is stored as in upstream. It's just itself unless the file is composite.
+    pub fn segments(&self) -> Vec<UploadTokenV1> {
+        if !self.is_composite() {
+            return vec![self.clone()];
+        }
+        let count = (self.file_size + self.segment_size - 1) / self.segment_size;
+        (0..count).map(|i| UploadTokenV1 {
+            file_id: self.file_id.wrapping_add(i),
+            file_size: i64::min(self.segment_size, self.file_size - i * self.segment_size),
+            thumbnail: false,
+            segment_size: 0,
+            encryption: self.encryption.clone(),
+            compression: None,
+            part_size: self.part_size,
+        }).collect()
+    }
+
+    /// Returns the segment which the part is stored in, and the part index in it.
+    pub fn locate_part(&self, part: i32) -> (UploadTokenV1, i32) {
+        if !self.is_composite() {
+            return (self.clone(), part);
+        }
+        let parts_per_segment = (self.segment_size / self.chunk_size() as i64) as i32;
+        let segment = part / parts_per_segment;
+        (self.segments().swap_remove(segment as usize), part % parts_per_segment)
+    }
+
+    /// Returns the exact size which the part should have, or `None` if the part is out of the file.
+    pub fn expected_part_size(&self, part: i32) -> Option<usize> {
+        if part < 0 || part >= self.total_parts() {
+            return None;
+        }
+        let chunk_size = self.chunk_size() as i64;
+        let offset = part as i64 * chunk_size;
+        Some(i64::min(chunk_size, self.file_size - offset) as usize)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expected_part_size() {
+        let token = UploadTokenV1 { file_size: 1_000_000, ..Default::default() };
+        assert_eq!(token.total_parts(), 2);
+        assert_eq!(token.expected_part_size(0), Some(MAX_PART_SIZE));
+        assert_eq!(token.expected_part_size(1), Some(1_000_000 - MAX_PART_SIZE));
+        assert_eq!(token.expected_part_size(2), None);
+        assert_eq!(token.expected_part_size(-1), None);
+    }
+
+    #[test]
+    fn expected_part_size_with_recorded_part_size() {
+        let mut token = UploadTokenV1 { file_size: 8192, ..Default::default() };
+        token.set_part_size(4096);
+        assert_eq!(token.total_parts(), 2);
+        assert_eq!(token.expected_part_size(1), Some(4096));
+        assert_eq!(token.expected_part_size(2), None);
+    }
+
+    #[test]
+    fn expected_part_size_of_encrypted_upload() {
+        let mut token = UploadTokenV1 {
+            file_size: 10_000,
+            encryption: Some(FileEncryption::default()),
+            ..Default::default()
+        };
+        token.set_part_size(4096);
+        // the content of a part is smaller by the tag
+        assert_eq!(token.chunk_size(), 4096 - TAG_SIZE);
+        assert_eq!(token.total_parts(), 3);
+        assert_eq!(token.expected_part_size(0), Some(4096 - TAG_SIZE));
+        assert_eq!(token.expected_part_size(2), Some(10_000 - 2 * (4096 - TAG_SIZE)));
+        assert_eq!(token.stored_size(), 10_000 + 3 * TAG_SIZE as i64);
+    }
+
+    fn composite_token() -> UploadTokenV1 {
+        UploadTokenV1 {
+            file_id: 100,
+            file_size: 2 * 1024 * 1024 + 1000,
+            segment_size: 1024 * 1024,
+            ..Default::default()
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn segments() {
+        let token = UploadTokenV1 { file_size: 1000, ..Default::default() };
+        assert_eq!(token.segments(), vec![token.clone()]);
+
+        let segments = composite_token().segments();
+        assert_eq!(segments.iter().map(|x| x.file_id).collect::<Vec<_>>(), vec![100, 101, 102]);
+        assert_eq!(segments.iter().map(|x| x.file_size).collect::<Vec<_>>(), vec![1024 * 1024, 1024 * 1024, 1000]);
+        assert!(segments.iter().all(|x| !x.is_composite()));
+    }
+
+    #[test]
+    fn segment_file_ids_wrap() {
+        let token = UploadTokenV1 { file_id: i64::MAX, ..composite_token() };
+        assert_eq!(token.segments().iter().map(|x| x.file_id).collect::<Vec<_>>(), vec![i64::MAX, i64::MIN, i64::MIN + 1]);
+    }
+
+    #[test]
+    fn locate_part() {
+        let token = composite_token();
+        assert_eq!(token.total_parts(), 5);
+        let located = (0..5).map(|part| {
+            let (segment, part) = token.locate_part(part);
+            (segment.file_id, part)
+        }).collect::<Vec<_>>();
+        assert_eq!(located, vec![(100, 0), (100, 1), (101, 0), (101, 1), (102, 0)]);
+        assert_eq!(token.locate_part(4).0.expected_part_size(0), Some(1000));
+    }
+
+    #[test]
+    fn locate_offset() {
+        let peer = PeerRef { kind: PeerKind::Channel as i32, id: 5, access_hash: 6 };
+        let composite = CompositeFileRefV1 {
+            file_size: 2500,
+            segment_size: 1000,
+            segments: (1..=3).map(|message_id| FileRefV1 { message_id, ..Default::default() }).collect(),
+            peer: Some(peer.clone()),
+            copy_id: 7,
+            ..Default::default()
+        };
+
+        let (segment, offset) = composite.locate(0).unwrap();
+        assert_eq!((segment.message_id, offset), (1, 0));
+        assert_eq!(segment.peer, Some(peer));
+        assert_eq!(segment.copy_id, 7);
+        assert_eq!(composite.locate(1999).map(|(x, offset)| (x.message_id, offset)), Some((2, 999)));
+        assert_eq!(composite.locate(2000).map(|(x, offset)| (x.message_id, offset)), Some((3, 0)));
+        assert!(composite.locate(2500).is_none());
+    }
+
+    #[test]
+    fn locate_offset_in_broken_ref() {
+        let composite = CompositeFileRefV1 {
+            file_size: 2500,
+            segment_size: 1000,
+            segments: vec![FileRefV1::default(); 2],
+            ..Default::default()
+        };
+        assert!(composite.locate(2100).is_none());
+        assert!(CompositeFileRefV1 { segment_size: 0, ..composite }.locate(0).is_none());
+    }
+}
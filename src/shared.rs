@@ -1,10 +1,326 @@
-use grammers_client::grammers_tl_types as tl;
+use axum::{body::Body, http::{HeaderMap, HeaderValue}, response::Response};
+use base64::Engine;
+use grammers_client::{grammers_tl_types as tl, Client, InvocationError};
 
-use crate::proto::{FileRef, FileRefV1};
+use prost::Message as _;
 
-pub const CHUNK_SIZE: usize = 512 * 1024;
+use crate::{config, crypto, proto::{CompositeFileRefV1, FileCompression, FileEncryption, FileRef, FileRefV1, PeerRef}};
 
-pub fn message_to_file_ref(message: &tl::types::Message) -> Option<FileRef> {
+/// Upstream accepts upload parts of a power of two up to this size.
+pub const MAX_PART_SIZE: usize = 512 * 1024;
+
+/// Smaller parts are allowed by upstream, but this keeps every stored part aligned for `GetFile`.
+pub const MIN_PART_SIZE: usize = 4 * 1024;
+
+/// `GetFile` reads a power of two up to this size, which doesn't cross a boundary of this size.
+pub const MAX_DOWNLOAD_CHUNK_SIZE: usize = 1024 * 1024;
+
+pub const MIN_DOWNLOAD_CHUNK_SIZE: usize = 4 * 1024;
+
+/// Max length of a media caption (in UTF-16 code units) for non-premium users.
+pub const MAX_CAPTION_LENGTH: usize = 1024;
+
+/// Max length of a text message (in UTF-16 code units) for non-premium users.
+pub const MAX_MESSAGE_LENGTH: usize = 4096;
+
+/// Messages per `GetHistory` request when searching for a message whose response was lost.
+const SENT_MESSAGE_LOOKUP_LIMIT: i32 = 100;
+
+/// Messages older than this (in seconds) are not searched, since a retried send is much newer.
+const SENT_MESSAGE_LOOKUP_AGE: i32 = 24 * 60 * 60;
+
+/// Max number of `GetHistory` requests for one search, in case the chat gets many messages.
+const SENT_MESSAGE_LOOKUP_PAGES: usize = 50;
+
+/// Max number of IDs in a `GetMessages` request.
+const GET_MESSAGES_LIMIT: usize = 100;
+
+/// Files at least this size are uploaded with `SaveBigFilePart`.
+pub const BIG_UPLOAD_THRESHOLD: i64 = 10 * 1024 * 1024;
+
+pub fn is_valid_part_size(size: usize) -> bool {
+    size.is_power_of_two() && (MIN_PART_SIZE..=MAX_PART_SIZE).contains(&size)
+}
+
+pub fn is_valid_download_chunk_size(size: usize) -> bool {
+    size.is_power_of_two() && (MIN_DOWNLOAD_CHUNK_SIZE..=MAX_DOWNLOAD_CHUNK_SIZE).contains(&size)
+}
+
+/// Whether the content type can be returned as `Content-Type`, also in multipart bodies where a line break would start another header.
+pub fn is_valid_content_type(input: &str) -> bool {
+    !input.contains(['\r', '\n']) && HeaderValue::from_str(input).is_ok()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(input: &str) -> Option<Vec<u8>> {
+    if input.len() % 2 != 0 {
+        return None;
+    }
+    (0..input.len()).step_by(2).map(|i| u8::from_str_radix(input.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Data which teleton stores in the message text of an uploaded file.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct FileCaption {
+    /// format version, currently 1
+    pub v: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// photos don't have a file name attribute, so it's kept here (also for encrypted and compressed files, which store another one)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// only for encrypted and compressed files, since the document has a neutral one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    /// user supplied key/value data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    /// set on the text message which describes a file stored as several documents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<Manifest>,
+    /// set on documents which are a segment of a larger file (the index of it)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub segment: Option<u32>,
+    /// `random_id` which the message was sent with, to find it when the response of sending it is lost.
+    /// Left out if the caption has no room for it, or would be empty without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub random_id: Option<i64>,
+    /// hex of the encoded `FileCompression`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+    /// hex of the encoded `FileEncryption`, only inside `sealed`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// base64 of the whole caption sealed with the server key, for encrypted files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub name: String,
+    pub mime_type: String,
+    pub file_size: i64,
+    pub segment_size: i64,
+    /// message ids of the segments, in order
+    pub segments: Vec<i32>,
+}
+
+impl Default for FileCaption {
+    fn default() -> FileCaption {
+        FileCaption {
+            v: 1,
+            sha256: None,
+            name: None,
+            mime_type: None,
+            metadata: None,
+            manifest: None,
+            segment: None,
+            random_id: None,
+            compression: None,
+            key: None,
+            sealed: None,
+        }
+    }
+}
+
+impl FileCaption {
+    pub fn parse(text: &str) -> Option<FileCaption> {
+        let caption: FileCaption = serde_json::from_str(text).ok()?;
+        if caption.v != 1 {
+            println!("unknown caption version {}", caption.v);
+            return None;
+        }
+        let sealed = match &caption.sealed {
+            Some(v) => v,
+            None => return Some(caption),
+        };
+        let sealed = base64::prelude::BASE64_STANDARD.decode(sealed).ok()?;
+        let inner = match crypto::unseal(&sealed) {
+            Some(v) => v,
+            None => {
+                println!("failed to unseal caption, is TELETON_SECRET_KEY changed?");
+                return None;
+            }
+        };
+        serde_json::from_slice(&inner).ok()
+    }
+
+    /// Returns an empty string if there is nothing to store.
+    pub fn to_text(&self) -> String {
+        if self.sha256.is_none() && self.name.is_none() && self.metadata.is_none() && self.manifest.is_none() && self.segment.is_none() && self.random_id.is_none() && self.sealed.is_none() {
+            return "".to_string();
+        }
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Wraps the caption so that only teleton can read it. Needs the server key.
+    pub fn seal(&self) -> FileCaption {
+        let sealed = crypto::seal(serde_json::to_string(self).unwrap().as_bytes()).expect("server key is needed to seal captions");
+        FileCaption {
+            sealed: Some(base64::prelude::BASE64_STANDARD.encode(sealed)),
+            ..Default::default()
+        }
+    }
+
+    pub fn encryption(&self) -> Option<FileEncryption> {
+        FileEncryption::decode(&from_hex(self.key.as_ref()?)?[..]).ok()
+    }
+
+    pub fn compression(&self) -> Option<FileCompression> {
+        FileCompression::decode(&from_hex(self.compression.as_ref()?)?[..]).ok()
+    }
+}
+
+/// Returns an error response unless the request carries the configured admin bearer token.
+pub fn check_admin_token(headers: &HeaderMap) -> Option<Response> {
+    let expected = match &config::get().admin_token {
+        Some(v) => v,
+        None => {
+            return Some(Response::builder().status(404).body(Body::from("not found")).unwrap());
+        }
+    };
+
+    let given = headers.get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    if given != Some(expected.as_str()) {
+        return Some(Response::builder()
+            .status(401)
+            .header("WWW-Authenticate", "Bearer")
+            .body(Body::from("invalid admin token"))
+        .unwrap());
+    }
+
+    None
+}
+
+pub async fn get_message(client: &Client, peer: Option<&PeerRef>, message_id: i32) -> Result<Option<tl::types::Message>, InvocationError> {
+    Ok(get_messages(client, peer, &[message_id]).await?.into_iter().next())
+}
+
+/// Returns messages which exist in the chat (`None` for Saved Messages), in the order of `message_ids`. Deleted ones are skipped.
+/// Returns the messages which exist, in batches since upstream accepts a limited number of IDs at once.
+pub async fn get_messages(client: &Client, peer: Option<&PeerRef>, message_ids: &[i32]) -> Result<Vec<tl::types::Message>, InvocationError> {
+    let mut messages = vec![];
+    for ids in message_ids.chunks(GET_MESSAGES_LIMIT) {
+        messages.extend(get_messages_batch(client, peer, ids).await?);
+    }
+    Ok(messages)
+}
+
+async fn get_messages_batch(client: &Client, peer: Option<&PeerRef>, message_ids: &[i32]) -> Result<Vec<tl::types::Message>, InvocationError> {
+    let id = message_ids.iter().map(|id| tl::enums::InputMessage::Id(tl::types::InputMessageId {
+        id: *id,
+    })).collect();
+    let res = match PeerRef::input_channel(peer) {
+        Some(channel) => client.invoke(&tl::functions::channels::GetMessages { channel, id }).await?,
+        // message IDs of users and basic groups are shared with Saved Messages
+        None => client.invoke(&tl::functions::messages::GetMessages { id }).await?,
+    };
+
+    let messages = match res {
+        tl::enums::messages::Messages::Messages(m) => m.messages,
+        tl::enums::messages::Messages::ChannelMessages(m) => m.messages,
+        _ => {
+            println!("not expected messages {:?}", res);
+            return Ok(vec![]);
+        }
+    };
+
+    Ok(messages.into_iter().filter_map(|x| match x {
+        tl::enums::Message::Empty(message_empty) => {
+            println!("message not found {:?}", message_empty);
+            None
+        },
+        tl::enums::Message::Message(message) => Some(message),
+        tl::enums::Message::Service(message_service) => {
+            println!("message is service message {:?}", message_service);
+            None
+        },
+    }).collect())
+}
+
+/// A document which teleton sent, to find the message when its caption has no `random_id`.
+pub struct SentDocument {
+    pub name: String,
+    pub size: i64,
+}
+
+impl SentDocument {
+    fn matches(&self, message: &tl::types::Message) -> bool {
+        let doc = match &message.media {
+            Some(tl::enums::MessageMedia::Document(tl::types::MessageMediaDocument { document: Some(tl::enums::Document::Document(doc)), .. })) => doc,
+            _ => return false,
+        };
+        doc.size == self.size && document_file_name(doc) == Some(self.name.as_str())
+    }
+}
+
+/// Finds a message which teleton sent with `random_id` among the recent messages, since upstream doesn't look up by it.
+///
+/// Captions which have no room for `random_id` don't have it, so the latest message of `document` without one is taken then.
+/// The history is searched back page by page, up to `SENT_MESSAGE_LOOKUP_AGE` old.
+pub async fn find_sent_message(client: &Client, peer: Option<&PeerRef>, random_id: i64, document: Option<&SentDocument>) -> Result<Option<tl::types::Message>, InvocationError> {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|x| x.as_secs() as i32).unwrap_or(0);
+    let min_date = now - SENT_MESSAGE_LOOKUP_AGE;
+
+    let mut offset_id = 0;
+    for _ in 0..SENT_MESSAGE_LOOKUP_PAGES {
+        let req = tl::functions::messages::GetHistory {
+            peer: PeerRef::input_peer(peer),
+            offset_id,
+            offset_date: 0,
+            add_offset: 0,
+            limit: SENT_MESSAGE_LOOKUP_LIMIT,
+            max_id: 0,
+            min_id: 0,
+            hash: 0,
+        };
+        let res = client.invoke(&req).await?;
+
+        let (messages, is_last) = match res {
+            tl::enums::messages::Messages::Messages(m) => (m.messages, true),
+            tl::enums::messages::Messages::Slice(m) => (m.messages, false),
+            tl::enums::messages::Messages::ChannelMessages(m) => (m.messages, false),
+            _ => {
+                println!("not expected messages {:?}", res);
+                return Ok(None);
+            }
+        };
+
+        let mut oldest = None;
+        for message in messages {
+            let (id, date) = match &message {
+                tl::enums::Message::Message(m) => (m.id, m.date),
+                tl::enums::Message::Service(m) => (m.id, m.date),
+                tl::enums::Message::Empty(m) => (m.id, now),
+            };
+            oldest = Some(oldest.map_or((id, date), |(x, y): (i32, i32)| (x.min(id), y.min(date))));
+            if let tl::enums::Message::Message(m) = message {
+                match FileCaption::parse(&m.message).and_then(|x| x.random_id) {
+                    Some(v) if v == random_id => return Ok(Some(m)),
+                    None if document.is_some_and(|x| x.matches(&m)) => return Ok(Some(m)),
+                    _ => {},
+                }
+            }
+        }
+
+        match oldest {
+            Some((id, date)) if !is_last && date >= min_date => offset_id = id,
+            _ => return Ok(None),
+        }
+    }
+
+    println!("gave up searching for random_id {} after {} pages", random_id, SENT_MESSAGE_LOOKUP_PAGES);
+    Ok(None)
+}
+
+pub fn message_document(message: &tl::types::Message) -> Option<&tl::types::Document> {
     let doc = match &message.media {
         None => {
             println!("upstream doesn't contains media in message {:?}", message);
@@ -26,25 +342,166 @@ pub fn message_to_file_ref(message: &tl::types::Message) -> Option<FileRef> {
             return None;
         },
     };
-    let doc = match doc {
-        tl::enums::Document::Document(document) => document,
+    match doc {
+        tl::enums::Document::Document(document) => Some(document),
         _ => {
             println!("upstream document isn't document {:?}", doc);
+            None
+        }
+    }
+}
+
+pub fn document_file_name(doc: &tl::types::Document) -> Option<&str> {
+    doc.attributes.iter().find_map(|x| match x {
+        tl::enums::DocumentAttribute::Filename(f) => Some(f.file_name.as_str()),
+        _ => None,
+    })
+}
+
+/// A file attached to a message, which is either a document or a photo.
+pub struct MessageFile {
+    pub id: i64,
+    pub access_hash: i64,
+    pub file_reference: Vec<u8>,
+    pub date: i32,
+    pub size: i64,
+    pub mime_type: String,
+    pub name: Option<String>,
+    /// type of the largest size, only for photos
+    pub photo_size_type: Option<String>,
+}
+
+pub fn message_file(message: &tl::types::Message) -> Option<MessageFile> {
+    let caption = FileCaption::parse(&message.message);
+    let photo = match &message.media {
+        Some(tl::enums::MessageMedia::Photo(photo)) => photo,
+        _ => {
+            let doc = message_document(message)?;
+            let caption = caption.unwrap_or_default();
+            return Some(MessageFile {
+                id: doc.id,
+                access_hash: doc.access_hash,
+                file_reference: doc.file_reference.clone(),
+                date: doc.date,
+                size: doc.size,
+                mime_type: caption.mime_type.unwrap_or_else(|| doc.mime_type.clone()),
+                name: caption.name.or_else(|| document_file_name(doc).map(|x| x.to_string())),
+                photo_size_type: None,
+            });
+        },
+    };
+    let photo = match &photo.photo {
+        Some(tl::enums::Photo::Photo(photo)) => photo,
+        v => {
+            println!("upstream photo isn't available {:?}", v);
             return None;
         }
     };
 
+    let largest = photo.sizes.iter().filter_map(|x| match x {
+        tl::enums::PhotoSize::Size(size) => Some((size.r#type.clone(), size.w * size.h, size.size as i64)),
+        tl::enums::PhotoSize::Progressive(size) => Some((size.r#type.clone(), size.w * size.h, *size.sizes.last()? as i64)),
+        _ => None,
+    }).max_by_key(|x| x.1);
+    let (size_type, _, size) = match largest {
+        Some(v) => v,
+        None => {
+            println!("upstream photo doesn't have downloadable sizes {:?}", photo);
+            return None;
+        }
+    };
+
+    Some(MessageFile {
+        id: photo.id,
+        access_hash: photo.access_hash,
+        file_reference: photo.file_reference.clone(),
+        date: photo.date,
+        size,
+        // upstream always stores photos as JPEG
+        mime_type: "image/jpeg".to_string(),
+        name: caption.and_then(|x| x.name),
+        photo_size_type: Some(size_type),
+    })
+}
+
+pub fn message_to_file_ref(message: &tl::types::Message) -> Option<FileRef> {
+    let file = message_file(message)?;
+
+    let caption = FileCaption::parse(&message.message).unwrap_or_default();
+    let encryption = caption.encryption();
+    let compression = caption.compression();
+    let sha256 = caption.sha256.and_then(|x| from_hex(&x)).unwrap_or_default();
+
     let file_ref = FileRefV1 {
         message_id: message.id,
-        document_id: doc.id,
-        file_reference: doc.file_reference.clone(),
-        access_hash: doc.access_hash,
-        file_size: doc.size,
+        document_id: file.id,
+        file_reference: file.file_reference,
+        access_hash: file.access_hash,
+        file_size: file.size,
+        sha256,
+        photo_size_type: file.photo_size_type.unwrap_or_default(),
+        encryption,
+        compression,
+        peer: None,
+        copy_id: 0,
     };
 
     let file_ref = FileRef {
         v1: Some(file_ref),
+        ..Default::default()
     };
 
     return Some(file_ref);
+}
+
+/// Builds a composite ref from the manifest message and its segment messages (in any order, extra ones are ignored).
+pub fn manifest_to_file_ref(message: &tl::types::Message, segments: &[tl::types::Message]) -> Option<FileRef> {
+    let caption = FileCaption::parse(&message.message)?;
+    let encryption = caption.encryption();
+    let manifest = caption.manifest?;
+
+    let segments = manifest.segments.iter().map(|id| {
+        let segment = match segments.iter().find(|x| x.id == *id) {
+            Some(v) => v,
+            None => {
+                println!("segment {} of manifest {} is missing", id, message.id);
+                return None;
+            }
+        };
+        message_to_file_ref(segment)?.v1
+    }).collect::<Option<Vec<_>>>()?;
+
+    let file_ref = CompositeFileRefV1 {
+        manifest_message_id: message.id,
+        file_size: manifest.file_size,
+        segment_size: manifest.segment_size,
+        segments,
+        sha256: caption.sha256.and_then(|x| from_hex(&x)).unwrap_or_default(),
+        encryption,
+        peer: None,
+        copy_id: 0,
+    };
+
+    Some(FileRef {
+        composite_v1: Some(file_ref),
+        ..Default::default()
+    })
+}
+
+/// Fetches the manifest message and its segments, and returns the manifest message with the latest composite ref.
+pub async fn get_composite_file_ref(client: &Client, peer: Option<&PeerRef>, manifest_message_id: i32) -> Result<Option<(tl::types::Message, FileRef)>, InvocationError> {
+    let message = match get_message(client, peer, manifest_message_id).await? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let segment_ids = match FileCaption::parse(&message.message).and_then(|x| x.manifest) {
+        Some(manifest) => manifest.segments,
+        None => return Ok(None),
+    };
+    let segments = get_messages(client, peer, &segment_ids).await?;
+
+    Ok(manifest_to_file_ref(&message, &segments).map(|mut x| {
+        x.set_peer(peer.cloned());
+        (message, x)
+    }))
 }
\ No newline at end of file
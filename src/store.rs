@@ -0,0 +1,157 @@
+use std::{path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Small state which is kept in memory, and written to a JSON file on every change.
+pub struct JsonFileStore<T> {
+    path: PathBuf,
+    /// the data, and the version of it which is bumped on every change
+    data: Mutex<(T, u64)>,
+    /// the version which is saved to the file. The lock is held while writing, so that an older one doesn't overwrite a newer one
+    saved: Arc<Mutex<u64>>,
+}
+
+impl<T: Serialize + DeserializeOwned + Default> JsonFileStore<T> {
+    /// Loads the file, or starts empty if it doesn't exist yet.
+    ///
+    /// A broken file is moved aside to `*.json.corrupt` and the store starts empty, since the state can be rebuilt.
+    pub fn load(path: PathBuf) -> JsonFileStore<T> {
+        let data = match std::fs::read(&path) {
+            Ok(v) => match serde_json::from_slice(&v) {
+                Ok(v) => v,
+                Err(e) => {
+                    let corrupt_path = path.with_extension("json.corrupt");
+                    println!("failed to parse {}, moving it to {} and starting empty: {}", path.display(), corrupt_path.display(), e);
+                    if let Err(e) = std::fs::rename(&path, &corrupt_path) {
+                        println!("failed to move {}: {:?}", path.display(), e);
+                    }
+                    T::default()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => T::default(),
+            Err(e) => panic!("Failed to read {}: {}", path.display(), e),
+        };
+
+        JsonFileStore {
+            path,
+            data: Mutex::new((data, 0)),
+            saved: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.data.lock().unwrap().0)
+    }
+
+    /// Changes the data and saves it. The file is written on a blocking thread, so that handlers don't wait for the disk.
+    pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let (res, bytes, version) = {
+            let mut data = self.data.lock().unwrap();
+            let res = f(&mut data.0);
+            data.1 += 1;
+            (res, serde_json::to_vec(&data.0).unwrap(), data.1)
+        };
+
+        let path = self.path.clone();
+        let saved = self.saved.clone();
+        let save = move || {
+            let mut saved = saved.lock().unwrap();
+            // a newer version is already saved
+            if *saved >= version {
+                return;
+            }
+            write_file(&path, &bytes);
+            *saved = version;
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(save);
+            },
+            Err(_) => save(),
+        }
+
+        res
+    }
+}
+
+fn write_file(path: &Path, bytes: &[u8]) {
+    // write to a temporary file first, so that a crash doesn't leave a broken file
+    let tmp_path = path.with_extension("json.tmp");
+    let saved = std::fs::write(&tmp_path, bytes)
+        .and_then(|_| std::fs::rename(&tmp_path, path));
+    if let Err(e) = saved {
+        println!("failed to save {}: {:?}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("teleton-store-test-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(path.with_extension("json.corrupt"));
+        path
+    }
+
+    #[test]
+    fn starts_empty_without_file() {
+        let store: JsonFileStore<HashMap<String, u32>> = JsonFileStore::load(temp_path("missing"));
+        assert!(store.read(|x| x.is_empty()));
+    }
+
+    #[test]
+    fn saves_changes() {
+        let path = temp_path("saves");
+        let store: JsonFileStore<HashMap<String, u32>> = JsonFileStore::load(path.clone());
+        assert_eq!(store.update(|x| x.insert("a".to_string(), 1)), None);
+        store.update(|x| x.insert("a".to_string(), 2));
+
+        let loaded: JsonFileStore<HashMap<String, u32>> = JsonFileStore::load(path.clone());
+        assert_eq!(loaded.read(|x| x.get("a").copied()), Some(2));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn saves_the_latest_change_in_runtime() {
+        let path = temp_path("runtime");
+        let store: JsonFileStore<HashMap<String, u32>> = JsonFileStore::load(path.clone());
+        for i in 0..100 {
+            store.update(|x| x.insert("a".to_string(), i));
+        }
+
+        // the file is written on a blocking thread
+        let mut saved = None;
+        for _ in 0..100 {
+            saved = std::fs::read(&path).ok().and_then(|x| serde_json::from_slice::<HashMap<String, u32>>(&x).ok());
+            if saved.as_ref().and_then(|x| x.get("a")) == Some(&99) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(saved.and_then(|x| x.get("a").copied()), Some(99));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn moves_corrupt_file_aside() {
+        let path = temp_path("corrupt");
+        std::fs::write(&path, b"{\"a\": 1").unwrap();
+
+        let store: JsonFileStore<HashMap<String, u32>> = JsonFileStore::load(path.clone());
+        assert!(store.read(|x| x.is_empty()));
+        assert!(!path.exists());
+        let corrupt_path = path.with_extension("json.corrupt");
+        assert_eq!(std::fs::read(&corrupt_path).unwrap(), b"{\"a\": 1");
+
+        // the store keeps working, and the broken file is kept for inspection
+        store.update(|x| x.insert("b".to_string(), 2));
+        let loaded: JsonFileStore<HashMap<String, u32>> = JsonFileStore::load(path.clone());
+        assert_eq!(loaded.read(|x| x.get("b").copied()), Some(2));
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(corrupt_path).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{net::SocketAddr, time::Duration};
 
 use grammers_client::{grammers_tl_types as tl, session::Session, Client, Config, InitParams, ReconnectionPolicy};
 
@@ -14,26 +14,50 @@ impl ReconnectionPolicy for ReConPolicy {
     }
 }
 
-pub async fn get_authorized_client() -> Client {
-    let session_path = std::env::var("TELETON_SESSION_PATH").expect("TELETON_SESSION_PATH is not specified");
+/// Settings from the environment, which every connection is made with.
+struct ClientEnv {
+    session_path: String,
+    api_id: i32,
+    api_hash: String,
+    proxy_url: Option<String>,
+}
 
-    let api_id = std::env::var("TELETON_API_ID").expect("TELETON_API_ID is not specified").parse().expect("Failed to parse TELETON_API_ID (should be integer)");
-    let api_hash = std::env::var("TELETON_API_HASH").expect("TELETON_API_HASH is not specified");
-    let proxy_url = match std::env::var("TELETON_PROXY") {
-        Ok(v) => Some(v),
-        Err(_) => None,
-    };
+impl ClientEnv {
+    fn load() -> ClientEnv {
+        let session_path = std::env::var("TELETON_SESSION_PATH").expect("TELETON_SESSION_PATH is not specified");
 
-    let config = Config {
-        session: Session::load_file_or_create(&session_path).unwrap(),
-        api_id,
-        api_hash: api_hash.clone(),
-        params: InitParams {
-            proxy_url: proxy_url.clone(),
-            reconnection_policy: &ReConPolicy,
-            ..Default::default()
-        },
-    };
+        let api_id = std::env::var("TELETON_API_ID").expect("TELETON_API_ID is not specified").parse().expect("Failed to parse TELETON_API_ID (should be integer)");
+        let api_hash = std::env::var("TELETON_API_HASH").expect("TELETON_API_HASH is not specified");
+        let proxy_url = match std::env::var("TELETON_PROXY") {
+            Ok(v) => Some(v),
+            Err(_) => None,
+        };
+
+        ClientEnv { session_path, api_id, api_hash, proxy_url }
+    }
+
+    fn config(&self, session: Session, server_addr: Option<SocketAddr>) -> Config {
+        Config {
+            session,
+            api_id: self.api_id,
+            api_hash: self.api_hash.clone(),
+            params: InitParams {
+                proxy_url: self.proxy_url.clone(),
+                reconnection_policy: &ReConPolicy,
+                server_addr,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+pub async fn get_authorized_client() -> Client {
+    let env = ClientEnv::load();
+    let session_path = &env.session_path;
+    let api_id = env.api_id;
+    let api_hash = &env.api_hash;
+
+    let config = env.config(Session::load_file_or_create(session_path).unwrap(), None);
 
     let mut client = Client::connect(config)
         .await
@@ -112,17 +136,8 @@ pub async fn get_authorized_client() -> Client {
 
                 println!("Migrating to DC {:?}", good_server);
 
-                let config = Config {
-                    session: Session::load_file_or_create(&session_path).unwrap(),
-                    api_id,
-                    api_hash: api_hash.clone(),
-                    params: InitParams {
-                        proxy_url: proxy_url.clone(),
-                        reconnection_policy: &ReConPolicy,
-                        server_addr: Some(format!("{}:{}", good_server.ip_address, good_server.port).parse().expect("Failed to parse DC ip address")),
-                        ..Default::default()
-                    },
-                };
+                let server_addr = format!("{}:{}", good_server.ip_address, good_server.port).parse().expect("Failed to parse DC ip address");
+                let config = env.config(Session::load_file_or_create(session_path).unwrap(), Some(server_addr));
 
                 client = Client::connect(config).await.expect("Failed to migrating DC");
 
@@ -149,8 +164,46 @@ pub async fn get_authorized_client() -> Client {
             }
         };
 
-        client.session().save_to_file(&session_path).unwrap();
+        client.session().save_to_file(session_path).unwrap();
     }
 
     client
 }
+
+/// Connects additional clients on the authorization saved by `get_authorized_client`, for spreading file transfers.
+///
+/// Connections which fail are skipped, since the main client can still do everything.
+///
+/// The session file is read once, and every client gets its own copy in memory. They are never saved back to the file,
+/// so they can't overwrite it concurrently with each other or with the main client (which only saves it when logging in).
+pub async fn connect_transfer_clients(count: usize) -> Vec<Client> {
+    let env = ClientEnv::load();
+    if count == 0 {
+        return vec![];
+    }
+
+    let session = match Session::load_file(&env.session_path) {
+        Ok(v) => v.save(),
+        Err(e) => {
+            println!("Failed to load session for transfer connections {:?}", e);
+            return vec![];
+        }
+    };
+
+    let mut clients = vec![];
+    for i in 0..count {
+        let session = match Session::load(&session) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Failed to copy session for transfer connection {:?}", e);
+                break;
+            }
+        };
+        match Client::connect(env.config(session, None)).await {
+            Ok(v) => clients.push(v),
+            Err(e) => println!("Failed to connect transfer connection {} {:?}", i, e),
+        }
+    }
+
+    clients
+}
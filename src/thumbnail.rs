@@ -0,0 +1,30 @@
+use std::io::Cursor;
+
+use image::codecs::jpeg::JpegEncoder;
+
+/// Max width/height of thumbnails (upstream doesn't accept larger ones).
+pub const THUMBNAIL_SIZE: u32 = 320;
+
+/// Files larger than this are not kept in memory for generating thumbnails.
+pub const MAX_THUMBNAIL_SOURCE_SIZE: i64 = 20 * 1024 * 1024;
+
+/// Generates a JPEG thumbnail from JPEG/PNG/WebP image. Returns `None` for other files.
+pub fn generate(data: &[u8]) -> Option<Vec<u8>> {
+    let image = match image::load_from_memory(data) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("failed to decode image for thumbnail {:?}", e);
+            return None;
+        }
+    };
+
+    let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8();
+
+    let mut out = Cursor::new(Vec::new());
+    if let Err(e) = JpegEncoder::new_with_quality(&mut out, 80).encode_image(&thumbnail) {
+        println!("failed to encode thumbnail {:?}", e);
+        return None;
+    }
+
+    Some(out.into_inner())
+}
@@ -0,0 +1,120 @@
+use std::{sync::{atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering}, Mutex}, time::{Duration, Instant}};
+
+use grammers_client::{grammers_tl_types as tl, Client, InvocationError};
+
+/// A connection is skipped for a while after this many transport errors in a row.
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+const UNHEALTHY_COOLDOWN: Duration = Duration::from_secs(30);
+
+struct Connection {
+    client: Client,
+    in_flight: AtomicUsize,
+    consecutive_failures: AtomicU32,
+    total_calls: AtomicU64,
+    total_failures: AtomicU64,
+    unhealthy_until: Mutex<Option<Instant>>,
+}
+
+impl Connection {
+    fn is_healthy(&self) -> bool {
+        match *self.unhealthy_until.lock().unwrap() {
+            Some(until) => Instant::now() >= until,
+            None => true,
+        }
+    }
+}
+
+/// Counts a call in flight until it's dropped, so that cancelled calls are not counted forever.
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl<'a> InFlight<'a> {
+    fn start(counter: &'a AtomicUsize) -> InFlight<'a> {
+        counter.fetch_add(1, Ordering::Relaxed);
+        InFlight(counter)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct ConnectionSummary {
+    pub index: usize,
+    pub healthy: bool,
+    pub in_flight: usize,
+    pub consecutive_failures: u32,
+    pub total_calls: u64,
+    pub total_failures: u64,
+}
+
+/// Connections on the same authorization which file transfer RPCs (`SaveFilePart`, `SaveBigFilePart`, `GetFile`) are spread over.
+///
+/// The first one is the main client, so the pool works with no additional connections too.
+pub struct TransferPool {
+    connections: Vec<Connection>,
+}
+
+impl TransferPool {
+    pub fn new(main: Client, additional: Vec<Client>) -> TransferPool {
+        let connections = std::iter::once(main).chain(additional).map(|client| Connection {
+            client,
+            in_flight: AtomicUsize::new(0),
+            consecutive_failures: AtomicU32::new(0),
+            total_calls: AtomicU64::new(0),
+            total_failures: AtomicU64::new(0),
+            unhealthy_until: Mutex::new(None),
+        }).collect();
+
+        TransferPool { connections }
+    }
+
+    /// Picks the healthy connection with the fewest calls in flight, or the least loaded one if none is healthy.
+    fn pick(&self) -> &Connection {
+        let healthy = self.connections.iter().filter(|x| x.is_healthy()).min_by_key(|x| x.in_flight.load(Ordering::Relaxed));
+        match healthy {
+            Some(v) => v,
+            None => self.connections.iter().min_by_key(|x| x.in_flight.load(Ordering::Relaxed)).unwrap(),
+        }
+    }
+
+    pub async fn invoke<R: tl::RemoteCall>(&self, request: &R) -> Result<R::Return, InvocationError> {
+        let connection = self.pick();
+        connection.total_calls.fetch_add(1, Ordering::Relaxed);
+        let in_flight = InFlight::start(&connection.in_flight);
+        let res = connection.client.invoke(request).await;
+        drop(in_flight);
+
+        match &res {
+            // errors from upstream (e.g. FILE_REFERENCE_EXPIRED) don't mean the connection is broken
+            Ok(_) | Err(InvocationError::Rpc(_)) => {
+                connection.consecutive_failures.store(0, Ordering::Relaxed);
+                *connection.unhealthy_until.lock().unwrap() = None;
+            },
+            Err(e) => {
+                connection.total_failures.fetch_add(1, Ordering::Relaxed);
+                let failures = connection.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures >= MAX_CONSECUTIVE_FAILURES {
+                    println!("transfer connection failed {} times in a row, skipping it for a while: {:?}", failures, e);
+                    *connection.unhealthy_until.lock().unwrap() = Some(Instant::now() + UNHEALTHY_COOLDOWN);
+                }
+            },
+        }
+
+        res
+    }
+
+    pub fn list(&self) -> Vec<ConnectionSummary> {
+        self.connections.iter().enumerate().map(|(index, x)| ConnectionSummary {
+            index,
+            healthy: x.is_healthy(),
+            in_flight: x.in_flight.load(Ordering::Relaxed),
+            consecutive_failures: x.consecutive_failures.load(Ordering::Relaxed),
+            total_calls: x.total_calls.load(Ordering::Relaxed),
+            total_failures: x.total_failures.load(Ordering::Relaxed),
+        }).collect()
+    }
+}
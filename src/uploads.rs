@@ -0,0 +1,472 @@
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use tokio::sync::broadcast;
+
+use crate::{media::{self, MediaInfo}, proto::UploadTokenV1, shared::to_hex, thumbnail::MAX_THUMBNAIL_SOURCE_SIZE};
+
+/// Upper bound of out-of-order bytes held per upload for checksum computation.
+const MAX_PENDING_CHECKSUM_BYTES: usize = 64 * 1024 * 1024;
+
+/// Upper bound of parts buffered for thumbnails across all uploads. Uploads which come later go without a thumbnail.
+const MAX_BUFFERED_THUMBNAIL_BYTES: usize = 256 * 1024 * 1024;
+
+/// Events buffered per upload for slow subscribers. Older ones are skipped if they fall behind.
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// In-memory state of an upload which isn't finalized yet.
+pub struct UploadSession {
+    pub file_size: i64,
+    pub total_parts: i32,
+    /// size of a part of the content, which is a frame if compressed
+    pub chunk_size: usize,
+    /// the content is recorded separately by frames, since the stored parts are compressed
+    pub compressed: bool,
+    /// parts which upstream accepted
+    pub received: BTreeSet<i32>,
+    /// bytes of the parts in `received`
+    pub received_bytes: i64,
+    /// parts which failed to be sent, and haven't been sent again yet
+    pub failed: BTreeSet<i32>,
+    pub checksum: ChecksumState,
+    /// the first part, once it's received (for content type detection and media probing)
+    pub head: Option<Vec<u8>>,
+    /// the last part received so far, for containers which have metadata at the end (e.g. MP4 `moov`)
+    pub tail: Option<(i32, Vec<u8>)>,
+    /// all received parts, only if the whole file is needed at finalize (e.g. for thumbnails)
+    pub buffered: Option<BTreeMap<i32, Vec<u8>>>,
+    /// bytes in `buffered`, which are counted in `buffered_total` too
+    buffered_bytes: usize,
+    /// bytes buffered by all sessions of the registry
+    buffered_total: Arc<AtomicUsize>,
+    pub started_at: Instant,
+    pub last_activity: Instant,
+}
+
+impl Drop for UploadSession {
+    fn drop(&mut self) {
+        self.buffered_total.fetch_sub(self.buffered_bytes, Ordering::Relaxed);
+    }
+}
+
+impl UploadSession {
+    fn new(token: &UploadTokenV1, buffered_total: Arc<AtomicUsize>) -> UploadSession {
+        UploadSession {
+            file_size: token.file_size,
+            total_parts: token.total_parts(),
+            chunk_size: match &token.compression {
+                Some(compression) => compression.frame_size as usize,
+                None => token.chunk_size(),
+            },
+            compressed: token.compression.is_some(),
+            received: BTreeSet::new(),
+            received_bytes: 0,
+            failed: BTreeSet::new(),
+            checksum: ChecksumState::default(),
+            head: None,
+            tail: None,
+            buffered: if token.thumbnail && token.file_size <= MAX_THUMBNAIL_SOURCE_SIZE { Some(BTreeMap::new()) } else { None },
+            buffered_bytes: 0,
+            buffered_total,
+            started_at: Instant::now(),
+            last_activity: Instant::now(),
+        }
+    }
+
+    /// Records a part which upstream accepted.
+    pub fn record_part(&mut self, part: i32, bytes: &[u8]) {
+        if self.received.insert(part) {
+            self.received_bytes += bytes.len() as i64;
+        }
+        self.failed.remove(&part);
+        if !self.compressed {
+            self.record_content(part, bytes);
+        }
+    }
+
+    /// Records a part of the content for checksums, content type detection, media probing and thumbnails.
+    pub fn record_content(&mut self, part: i32, bytes: &[u8]) {
+        self.checksum.update(part, bytes);
+        if part == 0 && self.head.is_none() {
+            self.head = Some(bytes.to_vec());
+        }
+        if part > 0 && self.tail.as_ref().map_or(true, |(p, _)| part > *p) {
+            self.tail = Some((part, bytes.to_vec()));
+        }
+        if let Some(buffered) = &mut self.buffered {
+            if buffered.contains_key(&part) {
+                return;
+            }
+            let total = self.buffered_total.fetch_add(bytes.len(), Ordering::Relaxed) + bytes.len();
+            self.buffered_bytes += bytes.len();
+            buffered.insert(part, bytes.to_vec());
+            // streams of unknown size can grow past the limit, give up the thumbnail then
+            if self.buffered_bytes as i64 > MAX_THUMBNAIL_SOURCE_SIZE {
+                self.release_buffered();
+            } else if total > MAX_BUFFERED_THUMBNAIL_BYTES {
+                println!("too many bytes are buffered for thumbnails, giving up the thumbnail");
+                self.release_buffered();
+            }
+        }
+    }
+
+    fn release_buffered(&mut self) {
+        self.buffered = None;
+        self.buffered_total.fetch_sub(self.buffered_bytes, Ordering::Relaxed);
+        self.buffered_bytes = 0;
+    }
+
+    /// Returns the whole file if it's buffered and all of `total_parts` are received.
+    pub fn buffered_file(&self, total_parts: i32) -> Option<Vec<u8>> {
+        let buffered = self.buffered.as_ref()?;
+        if buffered.len() != total_parts as usize || buffered.keys().last() != Some(&(total_parts - 1)) {
+            return None;
+        }
+        Some(buffered.values().flatten().copied().collect())
+    }
+
+    /// Guesses the content type from magic bytes.
+    pub fn detect_content_type(&self) -> Option<&'static str> {
+        infer::get(self.head.as_ref()?).map(|x| x.mime_type())
+    }
+
+    /// Reads video/audio attributes from the container headers.
+    pub fn probe_media(&self, file_size: i64) -> Option<MediaInfo> {
+        let tail = self.tail.as_ref().map(|(part, bytes)| (*part as u64 * self.chunk_size as u64, &bytes[..]));
+        media::probe(self.head.as_ref()?, tail, file_size as u64)
+    }
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum UploadEventKind {
+    PartReceived { part: i32 },
+    PartFailed { part: i32, error: String },
+    /// a part is sent again after it failed or was received
+    Retry { part: i32 },
+    Finalized { r#ref: String },
+}
+
+/// Progress of an upload, sent to subscribers of `/v1/upload/events`.
+#[derive(serde::Serialize, Clone)]
+pub struct UploadEvent {
+    #[serde(flatten)]
+    pub kind: UploadEventKind,
+    pub bytes_done: i64,
+    /// unknown for streaming uploads until they are finalized
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_total: Option<i64>,
+}
+
+impl UploadEvent {
+    pub fn name(&self) -> &'static str {
+        match self.kind {
+            UploadEventKind::PartReceived { .. } => "part_received",
+            UploadEventKind::PartFailed { .. } => "part_failed",
+            UploadEventKind::Retry { .. } => "retry",
+            UploadEventKind::Finalized { .. } => "finalized",
+        }
+    }
+
+    fn of_part(session: &UploadSession, kind: UploadEventKind) -> UploadEvent {
+        UploadEvent {
+            kind,
+            bytes_done: session.received_bytes,
+            bytes_total: if session.file_size > 0 { Some(session.file_size) } else { None },
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct UploadSummary {
+    pub file_id: i64,
+    pub file_size: i64,
+    pub total_parts: i32,
+    pub received_parts: usize,
+    pub age_secs: u64,
+    pub idle_secs: u64,
+}
+
+pub struct Checksums {
+    pub md5: String,
+    pub sha256: String,
+}
+
+/// Hashes parts in file order. Parts arriving early are held until the gap before them is filled.
+#[derive(Default)]
+pub struct ChecksumState {
+    next_part: i32,
+    md5: Md5,
+    sha256: Sha256,
+    pending: BTreeMap<i32, Vec<u8>>,
+    pending_bytes: usize,
+    /// too many bytes arrived out of order, so checksums are not available for this upload
+    abandoned: bool,
+}
+
+impl ChecksumState {
+    pub fn update(&mut self, part: i32, bytes: &[u8]) {
+        if self.abandoned || part < self.next_part || self.pending.contains_key(&part) {
+            return;
+        }
+
+        if part > self.next_part {
+            if self.pending_bytes + bytes.len() > MAX_PENDING_CHECKSUM_BYTES {
+                println!("too many out-of-order parts, giving up checksum computation");
+                self.abandoned = true;
+                self.pending.clear();
+                self.pending_bytes = 0;
+                return;
+            }
+            self.pending_bytes += bytes.len();
+            self.pending.insert(part, bytes.to_vec());
+            return;
+        }
+
+        self.md5.update(bytes);
+        self.sha256.update(bytes);
+        self.next_part += 1;
+
+        while let Some(bytes) = self.pending.remove(&self.next_part) {
+            self.pending_bytes -= bytes.len();
+            self.md5.update(&bytes);
+            self.sha256.update(&bytes);
+            self.next_part += 1;
+        }
+    }
+
+    /// Returns checksums of the whole file, if all of `total_parts` were hashed.
+    pub fn finish(&self, total_parts: i32) -> Option<Checksums> {
+        if self.abandoned || self.next_part != total_parts {
+            return None;
+        }
+
+        Some(Checksums {
+            md5: to_hex(&self.md5.clone().finalize()),
+            sha256: to_hex(&self.sha256.clone().finalize()),
+        })
+    }
+}
+
+/// Tracks in-progress uploads by `file_id`.
+///
+/// Tokens are self-contained, so a session is (re-)created on demand when it's not known (e.g. after restart).
+#[derive(Default)]
+pub struct UploadRegistry {
+    sessions: Mutex<HashMap<i64, Arc<Mutex<UploadSession>>>>,
+    /// `file_id`s of aborted uploads, kept until the idle TTL passes to reject late chunks
+    aborted: Mutex<HashMap<i64, Instant>>,
+    /// only for uploads which someone has subscribed to
+    events: Mutex<HashMap<i64, broadcast::Sender<UploadEvent>>>,
+    /// bytes buffered for thumbnails by all sessions
+    buffered_total: Arc<AtomicUsize>,
+}
+
+impl UploadRegistry {
+    pub fn new() -> UploadRegistry {
+        UploadRegistry::default()
+    }
+
+    pub fn with_session<R>(&self, token: &UploadTokenV1, f: impl FnOnce(&mut UploadSession) -> R) -> R {
+        let session = self.sessions.lock().unwrap()
+            .entry(token.file_id)
+            .or_insert_with(|| Arc::new(Mutex::new(UploadSession::new(token, self.buffered_total.clone()))))
+            .clone();
+        let mut session = session.lock().unwrap();
+        session.last_activity = Instant::now();
+        f(&mut session)
+    }
+
+    /// Tells subscribers that a part is being sent, if it's a retry.
+    pub fn start_part(&self, token: &UploadTokenV1, part: i32) {
+        let event = self.with_session(token, |s| {
+            if s.received.contains(&part) || s.failed.contains(&part) {
+                Some(UploadEvent::of_part(s, UploadEventKind::Retry { part }))
+            } else {
+                None
+            }
+        });
+        if let Some(event) = event {
+            self.emit(token.file_id, event);
+        }
+    }
+
+    /// Records a part which upstream accepted, and tells subscribers.
+    pub fn record_part(&self, token: &UploadTokenV1, part: i32, bytes: &[u8]) {
+        let event = self.with_session(token, |s| {
+            s.record_part(part, bytes);
+            UploadEvent::of_part(s, UploadEventKind::PartReceived { part })
+        });
+        self.emit(token.file_id, event);
+    }
+
+    pub fn fail_part(&self, token: &UploadTokenV1, part: i32, error: String) {
+        let event = self.with_session(token, |s| {
+            s.failed.insert(part);
+            UploadEvent::of_part(s, UploadEventKind::PartFailed { part, error })
+        });
+        self.emit(token.file_id, event);
+    }
+
+    /// Tells subscribers that the upload is finalized, and closes their streams.
+    pub fn notify_finalized(&self, file_id: i64, r#ref: String, file_size: i64) {
+        let sender = self.events.lock().unwrap().remove(&file_id);
+        if let Some(sender) = sender {
+            let _ = sender.send(UploadEvent {
+                kind: UploadEventKind::Finalized { r#ref },
+                bytes_done: file_size,
+                bytes_total: Some(file_size),
+            });
+        }
+    }
+
+    /// Returns `None` if the upload has no session, e.g. it's finished, aborted or forgotten.
+    pub fn subscribe(&self, token: &UploadTokenV1) -> Option<broadcast::Receiver<UploadEvent>> {
+        let sessions = self.sessions.lock().unwrap();
+        // so that the subscription expires with the session even if no chunk arrives
+        sessions.get(&token.file_id)?.lock().unwrap().last_activity = Instant::now();
+        Some(self.events.lock().unwrap()
+            .entry(token.file_id)
+            .or_insert_with(|| broadcast::channel(EVENT_CHANNEL_CAPACITY).0)
+            .subscribe())
+    }
+
+    fn emit(&self, file_id: i64, event: UploadEvent) {
+        if let Some(sender) = self.events.lock().unwrap().get(&file_id) {
+            // no receivers is not an error here
+            let _ = sender.send(event);
+        }
+    }
+
+    pub fn received_parts(&self, file_id: i64) -> BTreeSet<i32> {
+        let session = self.sessions.lock().unwrap().get(&file_id).cloned();
+        match session {
+            Some(s) => s.lock().unwrap().received.clone(),
+            None => BTreeSet::new(),
+        }
+    }
+
+    pub fn remove(&self, file_id: i64) {
+        self.sessions.lock().unwrap().remove(&file_id);
+    }
+
+    pub fn abort(&self, file_id: i64) {
+        self.remove(file_id);
+        // closes the event streams
+        self.events.lock().unwrap().remove(&file_id);
+        self.aborted.lock().unwrap().insert(file_id, Instant::now());
+    }
+
+    pub fn is_aborted(&self, file_id: i64) -> bool {
+        self.aborted.lock().unwrap().contains_key(&file_id)
+    }
+
+    /// Forgets uploads (and abort records) which have been idle longer than `ttl`, and returns how many uploads were forgotten.
+    pub fn expire_idle(&self, ttl: Duration) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, s| s.lock().unwrap().last_activity.elapsed() < ttl);
+        let expired = before - sessions.len();
+        self.events.lock().unwrap().retain(|file_id, _| sessions.contains_key(file_id));
+        drop(sessions);
+
+        self.aborted.lock().unwrap().retain(|_, aborted_at| aborted_at.elapsed() < ttl);
+
+        expired
+    }
+
+    pub fn list(&self) -> Vec<UploadSummary> {
+        let sessions: Vec<(i64, Arc<Mutex<UploadSession>>)> = self.sessions.lock().unwrap().iter().map(|(k, v)| (*k, v.clone())).collect();
+        sessions.into_iter().map(|(file_id, s)| {
+            let s = s.lock().unwrap();
+            UploadSummary {
+                file_id,
+                file_size: s.file_size,
+                total_parts: s.total_parts,
+                received_parts: s.received.len(),
+                age_secs: s.started_at.elapsed().as_secs(),
+                idle_secs: s.last_activity.elapsed().as_secs(),
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MD5_HELLO_WORLD: &str = "5eb63bbbe01eeed093cb22bb8f5acdc3";
+    const SHA256_HELLO_WORLD: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    fn assert_hello_world(state: &ChecksumState) {
+        let checksums = state.finish(3).unwrap();
+        assert_eq!(checksums.md5, MD5_HELLO_WORLD);
+        assert_eq!(checksums.sha256, SHA256_HELLO_WORLD);
+    }
+
+    #[test]
+    fn checksums_in_order() {
+        let mut state = ChecksumState::default();
+        state.update(0, b"hell");
+        state.update(1, b"o wo");
+        state.update(2, b"rld");
+        assert_hello_world(&state);
+    }
+
+    #[test]
+    fn checksums_out_of_order() {
+        let mut state = ChecksumState::default();
+        state.update(2, b"rld");
+        state.update(1, b"o wo");
+        assert!(state.finish(3).is_none());
+        state.update(0, b"hell");
+        assert_hello_world(&state);
+    }
+
+    #[test]
+    fn duplicate_parts_are_ignored() {
+        let mut state = ChecksumState::default();
+        state.update(1, b"o wo");
+        state.update(1, b"xxxx");
+        state.update(0, b"hell");
+        state.update(0, b"xxxx");
+        state.update(2, b"rld");
+        assert_hello_world(&state);
+    }
+
+    #[test]
+    fn missing_part() {
+        let mut state = ChecksumState::default();
+        state.update(0, b"hell");
+        state.update(2, b"rld");
+        assert!(state.finish(3).is_none());
+        assert!(state.finish(1).is_some());
+    }
+
+    #[test]
+    fn too_many_pending_bytes() {
+        let mut state = ChecksumState::default();
+        state.update(1, &vec![0; MAX_PENDING_CHECKSUM_BYTES]);
+        state.update(2, b"x");
+        state.update(0, b"x");
+        assert!(state.finish(3).is_none());
+        assert_eq!(state.pending_bytes, 0);
+    }
+
+    #[test]
+    fn subscribe_needs_session() {
+        let uploads = UploadRegistry::new();
+        let token = UploadTokenV1 { file_id: 1, file_size: 10, ..Default::default() };
+        assert!(uploads.subscribe(&token).is_none());
+        // subscribing doesn't start a session
+        assert!(uploads.list().is_empty());
+
+        uploads.with_session(&token, |_| {});
+        let mut receiver = uploads.subscribe(&token).unwrap();
+        uploads.remove(token.file_id);
+        uploads.notify_finalized(token.file_id, "ref".to_string(), 10);
+        assert!(matches!(receiver.try_recv().unwrap().kind, UploadEventKind::Finalized { .. }));
+        assert!(uploads.subscribe(&token).is_none());
+    }
+}
@@ -14,11 +14,10 @@ if (typeof r.token !== "string") {
     throw new Error(`!?`)
 }
 
-const { token } = r
+const { token, chunk_size: CHUNK_SIZE } = r
 
 const fd = fs.openSync(filePath, "r")
 
-const CHUNK_SIZE = 512 * 1024
 const uploads = []
 const hasher = crypto.createHash("md5")
 